@@ -14,9 +14,6 @@ struct Baz(String);
 
 fn main() {
     let mut d = Dispatcher::new();
-    let sleeper = d.spawn(dispatcher!{
-        Sleep, _ => exit!("done")
-    });
     let printer = d.spawn(dispatcher!{
         String, s => { 
             println!("printer: {}", s);
@@ -40,5 +37,5 @@ fn main() {
     
     d.send(test, Envelope::pack(Foo));
     d.send(test, Envelope::pack(Bar(42)));
-    d.run();
+    d.run_until_idle();
 }
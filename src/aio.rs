@@ -0,0 +1,364 @@
+//! Linux native AIO on `O_DIRECT` files, bypassing the page cache
+//!
+//! ```ignore
+//! let buffers = BufPool::new(64, 64 * 1024)?;
+//! let aio = spawn!(|id| aio::server(id, 128));
+//! let file = Rc::new(fs::File::open("/data/segment")?);
+//! send!(aio, Read { file, offset: 0, buf: buffers.get().unwrap(), reply: me });
+//! // me receives ReadDone { offset: 0, result: Ok(buf) }, buf holds the bytes read
+//! ```
+//!
+//! The kernel signals completions on an eventfd (`IOCB_FLAG_RESFD`) registered with the reactor.
+//! Offsets and lengths have to be multiples of the logical block size of the device,
+//! buffers from a `BufPool` are aligned to `ALIGN`.
+
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::time::Duration;
+use std::{fmt, slice};
+use crate::prelude::*;
+use crate::reactor::{self, WakeUp, Registered};
+use crate::eventfd::{EventFd, Flags};
+use crate::fs::File;
+use crate::io::{AIoContext, AIoError};
+use crate::sys::{Errno, aio::{Iocb, Op, IOCB_FLAG_RESFD}};
+
+/// alignment of the buffers, enough for any block device
+pub const ALIGN: usize = 4096;
+
+const EINVAL: Errno = libc::EINVAL as Errno;
+
+struct Buffers {
+    base: *mut u8,
+    layout: Layout,
+    size: usize,
+    free: RefCell<Vec<usize>>,
+}
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.base, self.layout) }
+    }
+}
+
+/// `count` buffers of `size` bytes, aligned to `ALIGN`
+#[derive(Clone)]
+pub struct BufPool(Rc<Buffers>);
+impl BufPool {
+    /// fails with `EINVAL` unless there is at least one buffer, `size` is a non-zero multiple of `ALIGN`,
+    /// and all of them fit in memory
+    pub fn new(count: usize, size: usize) -> Result<BufPool, Errno> {
+        if count == 0 || size == 0 || size % ALIGN != 0 {
+            return Err(EINVAL);
+        }
+        let len = count.checked_mul(size).ok_or(EINVAL)?;
+        let layout = Layout::from_size_align(len, ALIGN).map_err(|_| EINVAL)?;
+        let base = unsafe { alloc::alloc_zeroed(layout) };
+        if base.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Ok(BufPool(Rc::new(Buffers {
+            base,
+            layout,
+            size,
+            free: RefCell::new((0 .. count).rev().collect()),
+        })))
+    }
+
+    /// a free buffer, holding `buffer_size()` bytes. None if all are in use.
+    pub fn get(&self) -> Option<AlignedBuf> {
+        let index = self.0.free.borrow_mut().pop()?;
+        Some(AlignedBuf { pool: self.0.clone(), index, len: self.0.size })
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.0.size
+    }
+}
+
+/// a buffer of a `BufPool`. It goes back to the pool when dropped.
+pub struct AlignedBuf {
+    pool: Rc<Buffers>,
+    index: usize,
+    len: usize,
+}
+impl AlignedBuf {
+    pub fn capacity(&self) -> usize {
+        self.pool.size
+    }
+
+    /// the number of bytes to read or write. Panics if `len` exceeds the capacity.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.capacity());
+        self.len = len;
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        unsafe { self.pool.base.add(self.index * self.pool.size) }
+    }
+}
+impl Deref for AlignedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr(), self.len) }
+    }
+}
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr(), self.len) }
+    }
+}
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        self.pool.free.borrow_mut().push(self.index);
+    }
+}
+impl fmt::Debug for AlignedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AlignedBuf({} bytes)", self.len)
+    }
+}
+
+/// read `buf.len()` bytes at `offset`, answered with `ReadDone`
+#[derive(Debug)]
+pub struct Read {
+    pub file: Rc<File>,
+    pub offset: u64,
+    pub buf: AlignedBuf,
+    pub reply: Cid,
+}
+
+/// write `buf` at `offset`, answered with `WriteDone`.
+/// The file has to be opened for writing, see `File::create`.
+#[derive(Debug)]
+pub struct Write {
+    pub file: Rc<File>,
+    pub offset: u64,
+    pub buf: AlignedBuf,
+    pub reply: Cid,
+}
+
+/// the buffer is truncated to the bytes read, which are fewer at the end of the file
+#[derive(Debug)]
+pub struct ReadDone {
+    pub offset: u64,
+    pub result: Result<AlignedBuf, Errno>,
+}
+
+/// the number of bytes written. The buffer went back to its pool.
+#[derive(Debug)]
+pub struct WriteDone {
+    pub offset: u64,
+    pub result: Result<usize, Errno>,
+}
+
+enum Request {
+    Read(Read),
+    Write(Write),
+}
+impl Request {
+    fn reply(&self) -> Cid {
+        match *self {
+            Request::Read(ref r) => r.reply,
+            Request::Write(ref w) => w.reply,
+        }
+    }
+    fn fail(self, e: Errno) -> (Cid, Reply) {
+        match self {
+            Request::Read(r) => (r.reply, Reply::Read(ReadDone { offset: r.offset, result: Err(e) })),
+            Request::Write(w) => (w.reply, Reply::Write(WriteDone { offset: w.offset, result: Err(e) })),
+        }
+    }
+}
+
+enum Reply {
+    Read(ReadDone),
+    Write(WriteDone),
+}
+
+/// a submitted request
+struct InFlight {
+    // the kernel owns it and the buffer until the request completes
+    _iocb: Box<Iocb>,
+    request: Request,
+}
+
+fn errno(e: AIoError) -> Errno {
+    (match e {
+        AIoError::EventLimit | AIoError::KernelRessources => libc::EAGAIN,
+        AIoError::NotInitialized => libc::EINVAL,
+        AIoError::KernelOutOfMemory => libc::ENOMEM,
+        AIoError::NotImplemented => libc::ENOSYS,
+        AIoError::BadFileDescriptor => libc::EBADF,
+        AIoError::Interrupted => libc::EINTR,
+    }) as Errno
+}
+
+struct Aio {
+    ctx: Option<AIoContext>,
+    eventfd: Registered<EventFd>,
+    in_flight: HashMap<u64, InFlight>,
+    next_id: u64,
+
+    /// requests waiting for a free slot in the context
+    queue: VecDeque<Request>,
+
+    /// replies to requests that could not be submitted
+    failed: Vec<(Cid, Reply)>,
+}
+impl Aio {
+    fn new(id: Cid, depth: usize) -> Result<Aio, Errno> {
+        let eventfd = EventFd::new(0, Flags::NonBlock | Flags::CloseOnExec).map_err(|e| e.errno())?;
+        let eventfd = reactor::register_counter(eventfd, id)?;
+        let ctx = AIoContext::setup(depth).map_err(errno)?;
+        Ok(Aio {
+            ctx: Some(ctx),
+            eventfd,
+            in_flight: HashMap::new(),
+            next_id: 0,
+            queue: VecDeque::new(),
+            failed: vec![],
+        })
+    }
+    fn ctx(&self) -> &AIoContext {
+        self.ctx.as_ref().unwrap()
+    }
+
+    /// submit `request`, or queue it if the context is full.
+    /// Returns false if it failed right away, its reply is sent with the completions.
+    fn submit(&mut self, request: Request) -> bool {
+        if self.in_flight.len() == self.ctx().capacity() {
+            self.queue.push_back(request);
+            return true;
+        }
+        let (opcode, fd, buf, offset) = match request {
+            Request::Read(ref r) => (Op::Pread, r.file.as_raw_fd(), &r.buf, r.offset),
+            Request::Write(ref w) => (Op::Pwrite, w.file.as_raw_fd(), &w.buf, w.offset),
+        };
+        let id = self.next_id;
+        let iocb = Box::new(Iocb {
+            data: id,
+            opcode: opcode as u16,
+            fd: fd as u32,
+            buf: buf.ptr() as u64,
+            nbytes: buf.len() as u64,
+            offset: offset as i64,
+            flags: IOCB_FLAG_RESFD,
+            resfd: self.eventfd.as_raw_fd() as u32,
+            .. Iocb::default()
+        });
+        match unsafe { self.ctx().submit(&[&*iocb as *const Iocb]) } {
+            Ok(()) => {
+                self.next_id += 1;
+                self.in_flight.insert(id, InFlight { _iocb: iocb, request });
+                true
+            }
+            Err(AIoError::KernelRessources) if self.in_flight.len() > 0 => {
+                // retried when something completes
+                self.queue.push_front(request);
+                true
+            }
+            Err(e) => {
+                debug!("io_submit -> {:?}", e);
+                self.failed.push(request.fail(errno(e)));
+                let _ = self.eventfd.write(1);
+                false
+            }
+        }
+    }
+
+    /// collect the completed requests and submit queued ones in their place
+    fn reap(&mut self) -> Vec<(Cid, Reply)> {
+        let mut replies = vec![];
+        let mut events = Vec::with_capacity(self.ctx().capacity());
+        loop {
+            if let Err(e) = self.ctx().get_events(0, &mut events, Some(Duration::from_secs(0))) {
+                debug!("io_getevents -> {:?}", e);
+                break;
+            }
+            if events.len() == 0 {
+                break;
+            }
+            for event in events.drain(..) {
+                let done = match self.in_flight.remove(&event.data) {
+                    Some(done) => done,
+                    None => continue
+                };
+                let reply = done.request.reply();
+                let reply = match done.request {
+                    Request::Read(r) => {
+                        let result = match event.res {
+                            n if n >= 0 => {
+                                let mut buf = r.buf;
+                                buf.set_len(n as usize);
+                                Ok(buf)
+                            }
+                            e => Err(-e)
+                        };
+                        (reply, Reply::Read(ReadDone { offset: r.offset, result }))
+                    }
+                    Request::Write(w) => {
+                        let result = if event.res >= 0 { Ok(event.res as usize) } else { Err(-event.res) };
+                        (reply, Reply::Write(WriteDone { offset: w.offset, result }))
+                    }
+                };
+                replies.push(reply);
+            }
+            while self.in_flight.len() < self.ctx().capacity() {
+                let request = match self.queue.pop_front() {
+                    Some(r) => r,
+                    None => break
+                };
+                let queued = self.queue.len();
+                if self.submit(request) && self.queue.len() > queued {
+                    break;
+                }
+            }
+        }
+        replies.extend(self.failed.drain(..));
+        replies
+    }
+}
+impl Drop for Aio {
+    fn drop(&mut self) {
+        // waits for the requests in flight, so their buffers can be released
+        if let Some(ctx) = self.ctx.take() {
+            if let Err(e) = ctx.destroy() {
+                error!("aio: destroy -> {}", e);
+            }
+        }
+    }
+}
+
+/// serves `Read` and `Write` requests with up to `depth` of them in flight.
+/// If the context can't be set up, the server logs the error and ends.
+pub fn server(id: Cid, depth: usize) -> GenBox {
+    let aio = Aio::new(id, depth);
+
+    Box::pin(Box::new(move |_: ResumeArg| {
+        let mut aio = match aio {
+            Ok(aio) => aio,
+            Err(e) => {
+                error!("aio: setup -> {}", e);
+                done!();
+            }
+        };
+        loop {
+            recv!{
+                WakeUp, w => if w.token() == aio.eventfd.token() {
+                    for (cid, reply) in aio.reap() {
+                        match reply {
+                            Reply::Read(r) => send!(cid, r),
+                            Reply::Write(w) => send!(cid, w),
+                        }
+                    }
+                },
+                Read, r => { aio.submit(Request::Read(r)); },
+                Write, w => { aio.submit(Request::Write(w)); }
+            }
+        }
+    }))
+}
@@ -0,0 +1,650 @@
+//! A durable, append-only log of serde records, modeled on Erlang's disk_log
+//!
+//! ```ignore
+//! let log = DiskLog::open("/var/lib/myservice/journal", Options::default())?;
+//! let journal = spawn!(|id| disk_log::server::<Event>(id, log));
+//! send!(journal, Append { record: Event::Deposit(10), reply: Some(me) });
+//! // me receives Appended { offset } once the record is on disk
+//! send!(journal, Read { from: 0, max: 100, reply: me });
+//! // me receives Records { from: 0, result: Ok(vec![(0, Event::Deposit(10)), ..]) }
+//! ```
+//!
+//! The log is a directory of segments, each named after the offset of its first record.
+//! A record is its bincode encoding, preceded by its length and a CRC-32 of it.
+//! A segment is synced when the log moves on to the next one, so only the last segment can end in a torn record.
+//! There is no type tag, so the log stays readable by later builds as long as the type serializes the same.
+//! That is also why records don't go through `Sendable::encode`: its type tag only holds within one build.
+//! Records are written right away, and everything appended while an fsync is running
+//! is committed by the next one, so a busy log needs far fewer fsyncs than appends.
+//! The fsyncs and reads run on a thread of the log, not on the dispatcher.
+
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{mem, thread};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use serde::{ser::Serialize, de::DeserializeOwned};
+use crate::prelude::*;
+use crate::reactor::{self, WakeUp, Registered};
+use crate::eventfd::Notifier;
+
+/// length and checksum
+const HEADER_SIZE: usize = 8;
+const SUFFIX: &str = "log";
+
+pub struct Options {
+    /// start a new segment once the current one would grow beyond this.
+    /// A larger record gets a segment of its own.
+    pub segment_size: u64,
+
+    /// wait this long before committing appends, to gather more of them
+    pub commit_delay: Option<Duration>,
+}
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            segment_size: 64 * 1024 * 1024,
+            commit_delay: None,
+        }
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 (IEEE)
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Segment {
+    /// offset of the first record
+    start: u64,
+    path: PathBuf,
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", start, SUFFIX))
+}
+
+/// reads the records of a segment until the end of the file or the first invalid one
+struct SegmentReader {
+    file: io::BufReader<fs::File>,
+    len: u64,
+
+    /// file position after the last valid record
+    pos: u64,
+}
+impl SegmentReader {
+    fn open(path: &Path) -> io::Result<SegmentReader> {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(SegmentReader { file: io::BufReader::new(file), len, pos: 0 })
+    }
+
+    /// the next record. None at the end of the segment, or if the record is torn or corrupt.
+    fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut header = [0; HEADER_SIZE];
+        if !read_full(&mut self.file, &mut header)? {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if self.pos + (HEADER_SIZE + len) as u64 > self.len {
+            return Ok(None);
+        }
+        let mut data = vec![0; len];
+        if !read_full(&mut self.file, &mut data)? || crc32(&data) != crc {
+            return Ok(None);
+        }
+        self.pos += (HEADER_SIZE + len) as u64;
+        Ok(Some(data))
+    }
+
+    /// skip `n` records
+    fn skip(&mut self, n: u64) -> io::Result<bool> {
+        for _ in 0 .. n {
+            let mut header = [0; HEADER_SIZE];
+            if !read_full(&mut self.file, &mut header)? {
+                return Ok(false);
+            }
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+            self.file.seek(SeekFrom::Current(len as i64))?;
+            self.pos += HEADER_SIZE as u64 + len;
+        }
+        Ok(true)
+    }
+}
+
+/// false if the file ended before `buf` was filled
+fn read_full(file: &mut impl io::Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled ..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// read up to `max` records with offsets in `from .. end`
+fn read_segments(segments: &[Segment], from: u64, end: u64, max: usize) -> io::Result<Vec<(u64, Vec<u8>)>> {
+    let mut records = vec![];
+    let first = segments.iter().rposition(|s| s.start <= from).unwrap_or(0);
+    let mut offset = from;
+    for segment in &segments[first ..] {
+        if offset >= end || records.len() >= max {
+            break;
+        }
+        // a segment may end early if it was damaged
+        offset = offset.max(segment.start);
+        let mut reader = SegmentReader::open(&segment.path)?;
+        if !reader.skip(offset - segment.start)? {
+            continue;
+        }
+        while offset < end && records.len() < max {
+            match reader.next()? {
+                Some(data) => records.push((offset, data)),
+                None => break
+            }
+            offset += 1;
+        }
+    }
+    Ok(records)
+}
+
+fn encode<T: Serialize + ?Sized>(record: &T) -> io::Result<Vec<u8>> {
+    bincode::serialize(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// fails if a record is not a `T`, as far as bincode can tell
+fn decode<T: DeserializeOwned>(records: Vec<(u64, Vec<u8>)>) -> io::Result<Vec<(u64, T)>> {
+    records.into_iter().map(|(offset, data)| {
+        bincode::deserialize(&data)
+            .map(|record| (offset, record))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }).collect()
+}
+
+/// the segments of a log and the one records are appended to
+pub struct DiskLog {
+    dir: PathBuf,
+    options: Options,
+
+    /// ordered by offset, the last one is being written
+    segments: Vec<Segment>,
+    file: fs::File,
+
+    /// bytes in the current segment
+    size: u64,
+
+    /// offset of the next record
+    next: u64,
+
+    /// directories changed since the last commit
+    unsynced: Vec<fs::File>,
+
+    /// a failed write could not be undone, the end of the current segment is unknown
+    failed: bool,
+}
+impl DiskLog {
+    /// open the log in `dir`, creating it if needed.
+    /// A record at the end of the last segment that was not completely written before a crash is cut off.
+    /// Fails with `InvalidData` if an earlier segment is damaged, its records were committed.
+    pub fn open(dir: impl AsRef<Path>, options: Options) -> io::Result<DiskLog> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == SUFFIX) != Some(true) {
+                continue;
+            }
+            match path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                Some(start) => segments.push(Segment { start, path }),
+                None => debug!("ignoring {:?} in the log", path)
+            }
+        }
+        segments.sort_by_key(|s| s.start);
+
+        let (mut next, mut size) = (segments.first().map(|s| s.start).unwrap_or(0), 0);
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.start != next {
+                let e = format!("{:?} does not start where the log ends, at {}", segment.path, next);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            let mut reader = SegmentReader::open(&segment.path)?;
+            let mut count = 0;
+            while reader.next()?.is_some() {
+                count += 1;
+            }
+            next = segment.start + count;
+            size = reader.pos;
+            if reader.len > reader.pos {
+                // the earlier segments were synced before the next one was started
+                if i + 1 < segments.len() {
+                    let e = format!("{:?} is damaged at byte {}", segment.path, reader.pos);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+                warn!("cutting off {} bytes of a torn record in {:?}", reader.len - reader.pos, segment.path);
+                let file = OpenOptions::new().write(true).open(&segment.path)?;
+                file.set_len(reader.pos)?;
+                file.sync_all()?;
+            }
+        }
+        if segments.is_empty() {
+            let path = segment_path(&dir, next);
+            fs::File::create(&path)?;
+            fs::File::open(&dir)?.sync_all()?;
+            segments.push(Segment { start: next, path });
+        }
+        let file = OpenOptions::new().append(true).open(&segments.last().unwrap().path)?;
+
+        Ok(DiskLog { dir, options, segments, file, size, next, unsynced: vec![], failed: false })
+    }
+
+    /// offset of the next record
+    pub fn next_offset(&self) -> u64 {
+        self.next
+    }
+
+    /// write a record, which is durable after the next `commit`. Returns its offset.
+    /// If the write fails, what was written of it is cut off again. If even that fails, so do all later appends.
+    pub fn append<T: Serialize + ?Sized>(&mut self, record: &T) -> io::Result<u64> {
+        if self.failed {
+            return Err(io::Error::new(io::ErrorKind::Other, "an earlier append failed"));
+        }
+        let data = encode(record)?;
+        let data_len = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record larger than 4 GiB"))?;
+
+        let len = (HEADER_SIZE + data.len()) as u64;
+        if self.size > 0 && self.size + len > self.options.segment_size {
+            self.rotate()?;
+        }
+        let mut frame = Vec::with_capacity(HEADER_SIZE + data.len());
+        frame.extend_from_slice(&data_len.to_le_bytes());
+        frame.extend_from_slice(&crc32(&data).to_le_bytes());
+        frame.extend_from_slice(&data);
+        if let Err(e) = self.file.write_all(&frame) {
+            if let Err(e) = self.file.set_len(self.size) {
+                error!("disk_log: cutting off a partial record in {:?} -> {}", self.dir, e);
+                self.failed = true;
+            }
+            return Err(e);
+        }
+
+        self.size += len;
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+
+    /// sync the current segment and start the next one.
+    /// This blocks, but only once per segment, and `open` relies on it to tell a torn record from damage.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        let path = segment_path(&self.dir, self.next);
+        let file = OpenOptions::new().append(true).create_new(true).open(&path)?;
+        self.file = file;
+        // the new directory entry
+        self.unsynced.push(fs::File::open(&self.dir)?);
+        self.segments.push(Segment { start: self.next, path });
+        self.size = 0;
+        Ok(())
+    }
+
+    /// the files to sync so everything appended so far is durable
+    pub(crate) fn take_unsynced(&mut self) -> io::Result<Vec<fs::File>> {
+        let mut files = mem::take(&mut self.unsynced);
+        files.push(self.file.try_clone()?);
+        Ok(files)
+    }
+
+    /// make everything appended so far durable. This blocks, the server commits on its thread instead.
+    pub fn commit(&mut self) -> io::Result<()> {
+        for file in self.take_unsynced()? {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// read up to `max` records, starting at offset `from`. This blocks, like `commit`.
+    pub fn read<T: DeserializeOwned>(&self, from: u64, max: usize) -> io::Result<Vec<(u64, T)>> {
+        decode(read_segments(&self.segments, from, self.next, max)?)
+    }
+}
+
+/// append `record`. `reply` receives `Appended` once it is durable.
+#[derive(Debug)]
+pub struct Append<T> {
+    pub record: T,
+    pub reply: Option<Cid>,
+}
+
+#[derive(Debug)]
+pub struct Appended {
+    pub offset: u64,
+}
+
+/// read up to `max` records, starting at offset `from`. Answered with `Records`.
+#[derive(Debug)]
+pub struct Read {
+    pub from: u64,
+    pub max: usize,
+    pub reply: Cid,
+}
+
+/// the records read and their offsets. Fewer than requested at the end of the log.
+#[derive(Debug)]
+pub struct Records<T> {
+    pub from: u64,
+    pub result: io::Result<Vec<(u64, T)>>,
+}
+
+/// sent by the server to itself, after the appends that arrived with the first one
+#[derive(Debug)]
+struct Commit;
+
+pub(crate) enum Job {
+    Sync(Vec<fs::File>),
+    Read { segments: Vec<Segment>, from: u64, end: u64, max: usize, reply: Cid },
+}
+
+pub(crate) enum Done {
+    Synced(io::Result<()>),
+    Read { from: u64, reply: Cid, result: io::Result<Vec<(u64, Vec<u8>)>> },
+}
+
+/// the thread that syncs and reads for a server. It stops when this is dropped.
+pub(crate) struct Worker {
+    jobs: Sender<Job>,
+    done: Receiver<Done>,
+    pub(crate) notifier: Registered<Notifier>,
+}
+impl Worker {
+    pub(crate) fn new(id: Cid) -> io::Result<Worker> {
+        let notifier = Notifier::new()?;
+        let registered = reactor::register_counter(notifier.clone(), id)
+            .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+        let (jobs, job_rx) = unbounded::<Job>();
+        let (done_tx, done) = unbounded();
+        let remote = notifier.clone();
+        thread::Builder::new()
+            .name("disk_log".into())
+            .spawn(move || {
+                for job in job_rx.iter() {
+                    let done = match job {
+                        Job::Sync(files) => Done::Synced(files.iter().map(|f| f.sync_data()).collect()),
+                        Job::Read { segments, from, end, max, reply } => Done::Read {
+                            from, reply,
+                            result: read_segments(&segments, from, end, max)
+                        }
+                    };
+                    if done_tx.send(done).is_err() {
+                        break;
+                    }
+                    let _ = remote.notify();
+                }
+            })?;
+        Ok(Worker { jobs, done, notifier: registered })
+    }
+    pub(crate) fn submit(&self, job: Job) {
+        self.jobs.send(job).expect("disk_log thread is gone");
+    }
+    pub(crate) fn finished(&self) -> Vec<Done> {
+        self.done.try_iter().collect()
+    }
+}
+
+/// owns `log`, appends the records of type `T` it is sent and reads them back.
+/// If writing or syncing fails, the server terminates and unconfirmed appends may be lost.
+/// It also terminates if its thread can't be started.
+pub fn server<T: Serialize + DeserializeOwned + Message + 'static>(id: Cid, log: DiskLog) -> GenBox {
+    let mut log = log;
+    let worker = Worker::new(id);
+    let commit_delay = log.options.commit_delay;
+
+    Box::pin(Box::new(move |_: ResumeArg| {
+        let worker = match worker {
+            Ok(worker) => worker,
+            Err(e) => {
+                error!("disk_log: starting the worker -> {}", e);
+                done!();
+            }
+        };
+        // appended, waiting for the next commit
+        let mut appended: Vec<(Option<Cid>, u64)> = vec![];
+        // being committed
+        let mut committing: Option<Vec<(Option<Cid>, u64)>> = None;
+        let mut commit_sent = false;
+
+        loop {
+            recv!{
+                Append<T>, Append { record, reply } => {
+                    match log.append(&record) {
+                        Ok(offset) => appended.push((reply, offset)),
+                        Err(e) => {
+                            error!("disk_log: append to {:?} -> {}", log.dir, e);
+                            done!();
+                        }
+                    }
+                    if !commit_sent && committing.is_none() {
+                        commit_sent = true;
+                        match commit_delay {
+                            Some(delay) => send_after!(delay, id, Commit),
+                            None => send!(id, Commit)
+                        }
+                    }
+                },
+                Commit, _ => {
+                    commit_sent = false;
+                    if committing.is_none() && appended.len() > 0 {
+                        match log.take_unsynced() {
+                            Ok(files) => worker.submit(Job::Sync(files)),
+                            Err(e) => {
+                                error!("disk_log: {}", e);
+                                done!();
+                            }
+                        }
+                        committing = Some(mem::take(&mut appended));
+                    }
+                },
+                Read, Read { from, max, reply } => {
+                    worker.submit(Job::Read {
+                        segments: log.segments.clone(),
+                        from, end: log.next, max, reply
+                    });
+                },
+                WakeUp, w => if w.token() == worker.notifier.token() {
+                    for done in worker.finished() {
+                        match done {
+                            Done::Synced(Ok(())) => {
+                                let batch = committing.take().unwrap_or_default();
+                                for i in 0 .. batch.len() {
+                                    if let (Some(reply), offset) = batch[i] {
+                                        send!(reply, Appended { offset });
+                                    }
+                                }
+                                // appended while syncing
+                                if appended.len() > 0 && !commit_sent {
+                                    commit_sent = true;
+                                    send!(id, Commit);
+                                }
+                            }
+                            Done::Synced(Err(e)) => {
+                                error!("disk_log: sync of {:?} -> {}", log.dir, e);
+                                done!();
+                            }
+                            Done::Read { from, reply, result } => {
+                                let result = result.and_then(decode::<T>);
+                                send!(reply, Records { from, result });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an empty directory for one test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("emp-disk_log-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn options(segment_size: u64) -> Options {
+        Options { segment_size, commit_delay: None }
+    }
+
+    /// each record is 24 bytes with its header
+    fn append(log: &mut DiskLog, range: std::ops::Range<u64>) {
+        for i in range {
+            assert_eq!(log.append(&format!("record {}", i)).unwrap(), i);
+        }
+        log.commit().unwrap();
+    }
+
+    fn read_all(log: &DiskLog) -> Vec<(u64, String)> {
+        log.read(0, 100).unwrap()
+    }
+
+    fn segments(dir: &Path) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+        paths.sort();
+        paths
+    }
+
+    /// flip a bit at `pos` of `path`
+    fn corrupt(path: &Path, pos: usize) {
+        let mut data = fs::read(path).unwrap();
+        data[pos] ^= 1;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn a_torn_tail_is_cut_off() {
+        let dir = scratch("torn");
+        let mut log = DiskLog::open(&dir, Options::default()).unwrap();
+        append(&mut log, 0 .. 3);
+        drop(log);
+
+        let path = segments(&dir).pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        // the header of a 100 byte record, and only part of it
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(&[0; 7]).unwrap();
+        drop(file);
+
+        let mut log = DiskLog::open(&dir, Options::default()).unwrap();
+        assert_eq!(log.next_offset(), 3);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3 * 24);
+        append(&mut log, 3 .. 4);
+        assert_eq!(read_all(&log).len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_record_with_a_bad_checksum_ends_the_log() {
+        let dir = scratch("crc");
+        let mut log = DiskLog::open(&dir, Options::default()).unwrap();
+        append(&mut log, 0 .. 3);
+        drop(log);
+
+        // the data of the second record
+        corrupt(&segments(&dir)[0], 24 + HEADER_SIZE + 10);
+        let log = DiskLog::open(&dir, Options::default()).unwrap();
+        assert_eq!(log.next_offset(), 1);
+        assert_eq!(read_all(&log), [(0, "record 0".to_string())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_span_segments() {
+        let dir = scratch("rotate");
+        // two records per segment
+        let mut log = DiskLog::open(&dir, options(64)).unwrap();
+        append(&mut log, 0 .. 5);
+        assert_eq!(segments(&dir), [segment_path(&dir, 0), segment_path(&dir, 2), segment_path(&dir, 4)]);
+        assert_eq!(log.read::<String>(3, 1).unwrap(), [(3, "record 3".to_string())]);
+        drop(log);
+
+        let log = DiskLog::open(&dir, options(64)).unwrap();
+        assert_eq!(log.next_offset(), 5);
+        let expected: Vec<_> = (0 .. 5).map(|i| (i, format!("record {}", i))).collect();
+        assert_eq!(read_all(&log), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_damaged_earlier_segment_is_an_error() {
+        let dir = scratch("damaged-segment");
+        let mut log = DiskLog::open(&dir, options(64)).unwrap();
+        append(&mut log, 0 .. 5);
+        drop(log);
+
+        corrupt(&segment_path(&dir, 0), 24 + HEADER_SIZE + 10);
+        let e = DiskLog::open(&dir, options(64)).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        // nothing was removed
+        assert_eq!(segments(&dir).len(), 3);
+        assert_eq!(fs::metadata(segment_path(&dir, 0)).unwrap().len(), 2 * 24);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_record_larger_than_a_segment_gets_its_own() {
+        let dir = scratch("too-large");
+        let mut log = DiskLog::open(&dir, options(16)).unwrap();
+        append(&mut log, 0 .. 2);
+        assert_eq!(segments(&dir), [segment_path(&dir, 0), segment_path(&dir, 1)]);
+        drop(log);
+
+        // the segment size does not limit what can be read back
+        let log = DiskLog::open(&dir, options(16)).unwrap();
+        assert_eq!(log.next_offset(), 2);
+        assert_eq!(read_all(&log).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_smaller_segment_size_keeps_the_records() {
+        let dir = scratch("smaller");
+        let mut log = DiskLog::open(&dir, Options::default()).unwrap();
+        append(&mut log, 0 .. 3);
+        drop(log);
+
+        let mut log = DiskLog::open(&dir, options(32)).unwrap();
+        assert_eq!(log.next_offset(), 3);
+        append(&mut log, 3 .. 4);
+        assert_eq!(segments(&dir), [segment_path(&dir, 0), segment_path(&dir, 3)]);
+        assert_eq!(read_all(&log).len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,14 +5,28 @@ use std::ops::GeneratorState;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::pin::Pin;
 use std::future::Future;
+use std::time::{Duration, Instant};
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::thread;
+use std::rc::Rc;
 use std::task::{Context, Waker, Poll};
 use crate::message::*;
-use crate::epoll;
+use crate::epoll::EPoll;
+use crate::reactor::{self, Reactor, WakeUp};
+use crate::uring::{self, Uring};
+use crate::sys::Errno;
+use crate::trace::{Tracer, TraceEvent, TraceKind};
+use crate::timeline::Timeline;
+use crate::metrics::{Registry, DispatchMetrics};
+use crate::timer::{Clock, Timers};
+use crate::sim::Sim;
+use crate::reactor::FakeReactor;
+use crate::table::{self, Tables};
 use slotmap::{SlotMap, new_key_type, KeyData};
 use crossbeam::channel::{unbounded, Receiver, Sender};
 
 /// unique identifier for each coroutine
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Cid(ProcessKey);
 impl Cid {
     pub fn as_ffi(self) -> u64 {
@@ -29,9 +43,55 @@ pub struct ExitReason {
     pub msg: &'static str
 }
 
-/// internally used to signal that we are out of work.
+/// how a dispatcher waits for IO
+#[derive(Debug, Copy, Clone)]
+pub enum Backend {
+    EPoll,
+
+    /// an io_uring with `entries` submission slots
+    /// and `buffers` registered buffers of `buffer_size` bytes. See the `uring` module.
+    Uring { entries: u32, buffers: usize, buffer_size: usize },
+}
+impl Backend {
+    /// io_uring with a reasonable default size
+    pub fn uring() -> Backend {
+        Backend::Uring { entries: 256, buffers: 64, buffer_size: 16 * 1024 }
+    }
+}
+
+/// what the dispatcher does when no process is runnable.
+///
+/// It first polls without blocking `spins` times, then yields the thread
+/// and polls again `yields` times, and finally blocks in `epoll_wait`.
+#[derive(Debug, Copy, Clone)]
+pub struct IdleStrategy {
+    pub spins: usize,
+    pub yields: usize,
+}
+impl IdleStrategy {
+    /// block right away (the default)
+    pub fn block() -> IdleStrategy {
+        IdleStrategy { spins: 0, yields: 0 }
+    }
+
+    /// never block, keep polling
+    pub fn busy_poll() -> IdleStrategy {
+        IdleStrategy { spins: usize::MAX, yields: 0 }
+    }
+
+    pub fn spin_then_park(spins: usize, yields: usize) -> IdleStrategy {
+        IdleStrategy { spins, yields }
+    }
+}
+impl Default for IdleStrategy {
+    fn default() -> IdleStrategy {
+        IdleStrategy::block()
+    }
+}
+
+/// sent to linked and monitoring processes when a process terminates
 #[derive(Debug)]
-pub struct Sleep;
+pub struct Down(pub Cid);
 
 /// yield type for coroutines
 pub enum ProcessYield {
@@ -40,12 +100,19 @@ pub enum ProcessYield {
     
     /// send a message to …
     Send(Cid, Envelope),
+
+    /// send a message after the given delay
+    SendAfter(Duration, Cid, Envelope),
     
     /// spawn a coroutine (to be used with `Dispatcher::prepare_spawn`)
     Spawn(GenBox), 
     Spawn2(SpawnBox), 
 
     SpawnFut(FutBox),
+
+    /// monitor a process, see `Dispatcher::monitor`.
+    /// If it is already gone, `Down` is sent right away.
+    Monitor(Cid),
     
     /// waiting for IO
     Io
@@ -77,6 +144,60 @@ pub type SpawnBox = Box<dyn FnOnce(Cid) -> GenBox>;
 
 struct Process {
     generator: GenBox,
+    name: Option<String>,
+    reductions: u64,
+    status: Status,
+    links: Vec<ProcessKey>,
+    monitors: Vec<ProcessKey>,
+
+    /// the processes this one monitors
+    monitoring: Vec<ProcessKey>,
+    traced: bool,
+}
+impl Process {
+    fn new(generator: GenBox) -> Process {
+        Process {
+            generator,
+            name: None,
+            reductions: 0,
+            status: Status::Waiting,
+            links: vec![],
+            monitors: vec![],
+            monitoring: vec![],
+            traced: false,
+        }
+    }
+}
+
+/// what a process is currently doing
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// queued to be resumed
+    Runnable,
+
+    /// waiting for a message
+    Waiting,
+
+    /// waiting for IO
+    Io,
+}
+
+/// snapshot of a process, as returned by `Dispatcher::processes`
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub cid: Cid,
+    pub name: Option<String>,
+
+    /// number of messages queued for this process
+    pub mailbox: usize,
+
+    /// number of times the process has been resumed
+    pub reductions: u64,
+    pub status: Status,
+    pub links: Vec<Cid>,
+
+    /// processes monitoring this one
+    pub monitors: Vec<Cid>,
 }
 
 pub struct PreparedCoro {
@@ -99,27 +220,90 @@ pub struct Dispatcher {
     futures: SlotMap<FutureKey, (FutBox, Waker)>,
     queue: VecDeque<(ProcessKey, ResumeArg)>,
     queue2: Option<VecDeque<(ProcessKey, ResumeArg)>>,
+    names: HashMap<String, ProcessKey>,
     exit: Option<ExitReason>,
     wake_rx: Option<Receiver<FutureKey>>,
     wake_tx: Sender<FutureKey>,
-    sleeper: Option<ProcessKey>,
+    reactor: Rc<dyn Reactor>,
+
+    /// messages from the last `Reactor::wait`
+    ready: Vec<(Cid, Envelope)>,
+
+    /// the reactor again, if it is a ring
+    uring: Option<Rc<Uring>>,
+    tracer: Option<Tracer>,
+    trace_all: bool,
+    timeline: Option<Timeline>,
+    registry: Registry,
+    metrics: DispatchMetrics,
+    clock: Clock,
+    timers: Timers,
+    sim: Option<Sim>,
+    idle: IdleStrategy,
+
+    /// the `table`s of the processes
+    tables: Rc<Tables>,
 }
 impl Dispatcher {
     pub fn new() -> Dispatcher {
+        Dispatcher::with_backend(Backend::EPoll).unwrap()
+    }
+
+    pub fn with_backend(backend: Backend) -> Result<Dispatcher, Errno> {
+        Ok(match backend {
+            Backend::EPoll => Dispatcher::with_reactor(Rc::new(EPoll::new())),
+            Backend::Uring { entries, buffers, buffer_size } => {
+                let ring = Rc::new(Uring::new(entries, buffers, buffer_size)?);
+                let mut d = Dispatcher::with_reactor(ring.clone());
+                d.uring = Some(ring);
+                d
+            }
+        })
+    }
+
+    pub fn with_reactor(reactor: Rc<dyn Reactor>) -> Dispatcher {
+        Dispatcher::with_clock(Clock::Real(Instant::now()), reactor)
+    }
+
+    /// a dispatcher without real IO, scheduled by `sim`
+    pub(crate) fn simulated(sim: Sim) -> Dispatcher {
+        let mut d = Dispatcher::manual(Rc::new(FakeReactor::new()));
+        d.sim = Some(sim);
+        d
+    }
+
+    /// a dispatcher on virtual time, driven by hand
+    pub(crate) fn manual(reactor: Rc<FakeReactor>) -> Dispatcher {
+        Dispatcher::with_clock(Clock::Virtual(Duration::from_secs(0)), reactor)
+    }
+
+    fn with_clock(clock: Clock, reactor: Rc<dyn Reactor>) -> Dispatcher {
         let (wake_tx, wake_rx) = unbounded();
-        let mut d = Dispatcher {
+        let registry = Registry::new();
+        let metrics = DispatchMetrics::new(&registry);
+        Dispatcher {
             processes: SlotMap::with_key(),
             futures: SlotMap::with_key(),
             queue: VecDeque::new(),
             queue2: Some(VecDeque::new()),
+            names: HashMap::new(),
             exit: None,
             wake_rx: Some(wake_rx),
             wake_tx,
-            sleeper: None,
-        };
-        let s = d.spawn(epoll::sleeper());
-        d.sleeper = Some(s.0);
-        d
+            reactor,
+            ready: Vec::new(),
+            uring: None,
+            tracer: None,
+            trace_all: false,
+            timeline: None,
+            registry,
+            metrics,
+            clock,
+            timers: Timers::new(),
+            sim: None,
+            idle: IdleStrategy::default(),
+            tables: Rc::new(Tables::new()),
+        }
     }
 
     pub fn spawn2(&mut self, f: Box<dyn FnOnce(Cid) -> GenBox>) -> Cid {
@@ -131,14 +315,215 @@ impl Dispatcher {
     }
 
     fn spawn3(&mut self, f: impl FnOnce(Cid) -> GenBox) -> Cid {
-        Cid(self.processes.insert_with_key(|key| {
+        let _enter = self.enter();
+        let mut ended = None;
+        let mut first = None;
+        let cid = Cid(self.processes.insert_with_key(|key| {
+            let _caller = table::caller(Cid(key));
             let mut generator = f(Cid(key));
-            generator.as_mut().resume(ResumeArg::Empty);
+            match generator.as_mut().resume(ResumeArg::Empty) {
+                GeneratorState::Complete(e) => ended = Some(e),
+                GeneratorState::Yielded(y) => first = Some(y),
+            }
+
+            Process::new(generator)
+        }));
+        self.metrics.spawns.inc();
+        self.metrics.processes.set(self.processes.len() as f64);
 
-            Process {
-                generator,
+        // it ended before taking a message, like a server that failed to set up
+        if let Some(e) = ended {
+            self.remove_process(cid.0);
+            if let ProcessExit::Terminate(reason) = e {
+                self.exit = Some(reason);
             }
-        }))
+        }
+        // it did something before taking a message, like monitoring another process
+        if let Some(arg) = first.and_then(|y| self.yielded(cid.0, y)) {
+            self.set_status(cid.0, Status::Runnable);
+            self.queue.push_back((cid.0, arg));
+        }
+        cid
+    }
+
+    /// the metrics registry of this dispatcher.
+    /// Processes can register their own metrics on it.
+    pub fn metrics(&self) -> Registry {
+        self.registry.clone()
+    }
+
+    /// register `cid` under `name`. returns false if the name is already taken.
+    pub fn register(&mut self, name: impl Into<String>, cid: Cid) -> bool {
+        let name = name.into();
+        let process = match self.processes.get_mut(cid.0) {
+            Some(p) => p,
+            None => return false
+        };
+        if self.names.contains_key(&name) {
+            return false;
+        }
+        if let Some(old) = process.name.replace(name.clone()) {
+            self.names.remove(&old);
+        }
+        self.names.insert(name, cid.0);
+        true
+    }
+
+    pub fn unregister(&mut self, name: &str) {
+        if let Some(key) = self.names.remove(name) {
+            if let Some(p) = self.processes.get_mut(key) {
+                p.name = None;
+            }
+        }
+    }
+
+    /// look up a registered process
+    pub fn whereis(&self, name: &str) -> Option<Cid> {
+        self.names.get(name).map(|&key| Cid(key))
+    }
+
+    /// link two processes. Whichever terminates first sends `Down` to the other.
+    pub fn link(&mut self, a: Cid, b: Cid) {
+        if !self.processes.contains_key(a.0) || !self.processes.contains_key(b.0) {
+            return;
+        }
+        for &(x, y) in &[(a.0, b.0), (b.0, a.0)] {
+            let links = &mut self.processes[x].links;
+            if !links.contains(&y) {
+                links.push(y);
+            }
+        }
+    }
+
+    /// `watcher` receives `Down(target)` when `target` terminates, once however often it monitors it
+    pub fn monitor(&mut self, watcher: Cid, target: Cid) {
+        if !self.processes.contains_key(watcher.0) || !self.processes.contains_key(target.0) {
+            return;
+        }
+        let monitors = &mut self.processes[target.0].monitors;
+        if !monitors.contains(&watcher.0) {
+            monitors.push(watcher.0);
+            self.processes[watcher.0].monitoring.push(target.0);
+        }
+    }
+
+    /// set where trace events go. Nothing is traced until `trace` or `trace_all` is used.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn clear_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// enable or disable tracing of a single process.
+    /// processes spawned by a traced process are traced as well.
+    pub fn trace(&mut self, cid: Cid, on: bool) {
+        if let Some(p) = self.processes.get_mut(cid.0) {
+            p.traced = on;
+        }
+    }
+
+    /// enable or disable tracing for all processes
+    pub fn trace_all(&mut self, on: bool) {
+        self.trace_all = on;
+    }
+
+    fn is_traced(&self, proc_id: ProcessKey) -> bool {
+        match self.tracer {
+            None => false,
+            Some(Tracer::Process(tracer)) if tracer.0 == proc_id => false,
+            Some(_) => self.trace_all || self.processes.get(proc_id).map(|p| p.traced).unwrap_or(false)
+        }
+    }
+
+    fn emit(&mut self, proc_id: ProcessKey, kind: TraceKind) {
+        let event = TraceEvent {
+            time: Instant::now(),
+            cid: Cid(proc_id),
+            kind
+        };
+        match self.tracer {
+            Some(Tracer::Process(tracer)) => self.queue.push_back((tracer.0, ResumeArg::Message(Envelope::pack(event)))),
+            Some(Tracer::Callback(ref mut f)) => f(&event),
+            None => {}
+        }
+    }
+
+    /// start recording scheduling spans and message latencies
+    pub fn record_timeline(&mut self) {
+        if self.timeline.is_none() {
+            self.timeline = Some(Timeline::new());
+        }
+    }
+
+    /// stop recording and return what was recorded so far
+    pub fn take_timeline(&mut self) -> Option<Timeline> {
+        self.timeline.take()
+    }
+
+    /// information about all processes
+    pub fn processes(&self) -> Vec<ProcessInfo> {
+        self.processes.keys().filter_map(|key| self.process_info(Cid(key))).collect()
+    }
+
+    /// information about a single process, or None if it does not exist
+    pub fn process_info(&self, cid: Cid) -> Option<ProcessInfo> {
+        let p = self.processes.get(cid.0)?;
+        let mailbox = self.queue.iter()
+            .filter(|&&(key, ref arg)| key == cid.0 && matches!(arg, ResumeArg::Message(_)))
+            .count();
+        let status = match p.status {
+            Status::Waiting if self.queue.iter().any(|&(key, _)| key == cid.0) => Status::Runnable,
+            status => status
+        };
+
+        Some(ProcessInfo {
+            cid,
+            name: p.name.clone(),
+            mailbox,
+            reductions: p.reductions,
+            status,
+            links: p.links.iter().map(|&key| Cid(key)).collect(),
+            monitors: p.monitors.iter().map(|&key| Cid(key)).collect(),
+        })
+    }
+
+    fn remove_process(&mut self, proc_id: ProcessKey) {
+        if self.is_traced(proc_id) {
+            self.emit(proc_id, TraceKind::Exit);
+        }
+        let process = match self.processes.remove(proc_id) {
+            Some(p) => p,
+            None => return
+        };
+        self.metrics.exits.inc();
+        self.metrics.processes.set(self.processes.len() as f64);
+        if let Some(name) = process.name {
+            self.names.remove(&name);
+        }
+        self.tables.owner_exited(Cid(proc_id));
+        if let Some(ref mut timeline) = self.timeline {
+            timeline.exited(Cid(proc_id));
+        }
+        for key in process.links {
+            if let Some(p) = self.processes.get_mut(key) {
+                p.links.retain(|&k| k != proc_id);
+                self.send(Cid(key), Envelope::pack(Down(Cid(proc_id))));
+            }
+        }
+        for key in process.monitors {
+            if let Some(p) = self.processes.get_mut(key) {
+                p.monitoring.retain(|&k| k != proc_id);
+                self.send(Cid(key), Envelope::pack(Down(Cid(proc_id))));
+            }
+        }
+        // a dead watcher is not notified
+        for key in process.monitoring {
+            if let Some(p) = self.processes.get_mut(key) {
+                p.monitors.retain(|&k| k != proc_id);
+            }
+        }
     }
 
     fn spawn_fut(&mut self, fut: FutBox) {
@@ -153,46 +538,98 @@ impl Dispatcher {
             };
             (fut, waker)
         });
+        self.metrics.futures.set(self.futures.len() as f64);
     }
 
     pub fn send(&mut self, addr: Cid, msg: Envelope) {
+        self.send_from(None, addr, msg);
+    }
+
+    fn send_from(&mut self, from: Option<Cid>, addr: Cid, mut msg: Envelope) {
         //println!("send {:?} to {:?}", msg, addr);
+        if let (Some(timeline), true) = (self.timeline.as_mut(), self.processes.contains_key(addr.0)) {
+            msg.flow = timeline.sent(from, addr, msg.type_name);
+        }
+        self.metrics.messages.inc();
         self.queue.push_back((addr.0, ResumeArg::Message(msg)));
     }
 
+    /// deliver `msg` to `addr` once `delay` has passed
+    pub fn send_after(&mut self, delay: Duration, addr: Cid, msg: Envelope) {
+        let deadline = self.clock.now() + delay;
+        self.timers.insert(deadline, addr, msg);
+    }
+
+    /// time since the dispatcher was created. Virtual time for simulated dispatchers.
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// queue all timers that are due
+    fn fire_timers(&mut self) {
+        if self.timers.is_empty() {
+            return;
+        }
+        let now = self.clock.now();
+        while let Some((addr, msg)) = self.timers.pop_due(now) {
+            self.send_from(None, addr, msg);
+        }
+    }
+
     fn run_one(&mut self, proc_id: ProcessKey, arg: ResumeArg) {
         let mut next_arg = Some(arg);
         
         while let Some(arg) = next_arg.take() {
+            // a process that yielded `Send` is resumed with `Empty` before it can take messages again
+            if let ResumeArg::Message(_) = arg {
+                if self.processes.get(proc_id).map(|p| p.status) == Some(Status::Runnable) {
+                    self.queue.push_back((proc_id, arg));
+                    return;
+                }
+            }
+            if let ResumeArg::Message(ref msg) = arg {
+                if let (Some(timeline), true) = (self.timeline.as_mut(), msg.flow != 0) {
+                    timeline.received(msg.flow, Cid(proc_id), msg.type_name);
+                }
+                if self.is_traced(proc_id) {
+                    let kind = match msg.type_id {
+                        id if id == TypeId::of::<WakeUp>() => TraceKind::WakeUp,
+                        _ => TraceKind::Receive { msg: msg.type_name }
+                    };
+                    self.emit(proc_id, kind);
+                }
+            }
+
             let process = match self.processes.get_mut(proc_id) {
                 None => return,
                 Some(p) => p,
             };
             
             //println!("running {:?}({:?})", proc_id, arg);
-            let state = process.generator.as_mut().resume(arg);
+            process.reductions += 1;
+            process.status = Status::Runnable;
+            let start = self.timeline.as_ref().map(|_| Instant::now());
+            let state = {
+                let _caller = table::caller(Cid(proc_id));
+                process.generator.as_mut().resume(arg)
+            };
+            if let (Some(timeline), Some(start)) = (self.timeline.as_mut(), start) {
+                let name = match self.processes.get(proc_id) {
+                    Some(&Process { name: Some(ref name), .. }) => name,
+                    _ => "resume"
+                };
+                timeline.span(name, Some(Cid(proc_id)), start, Instant::now());
+            }
             match state {
-                GeneratorState::Yielded(y) => match y { 
-                    ProcessYield::Send(addr, msg) => {
-                        self.send(addr, msg);
-                    }
-                    ProcessYield::Spawn(coro) => {
-                        let cid = self.spawn(coro);
-                        next_arg = Some(ResumeArg::Spawned(cid));
-                    }
-                    ProcessYield::Spawn2(f) => {
-                        let cid = self.spawn2(f);
-                        next_arg = Some(ResumeArg::Spawned(cid));
-                    }
-                    ProcessYield::SpawnFut(fut) => {
-                        self.spawn_fut(fut);
-                    }
-                    ProcessYield::Empty => return,
-                    ProcessYield::Io => return,
+                GeneratorState::Yielded(y) => match self.yielded(proc_id, y) {
+                    // back of the queue, so other processes get to run
+                    Some(ResumeArg::Empty) => {}
+                    Some(arg) => next_arg = Some(arg),
+                    None => return
                 },
                 GeneratorState::Complete(e) => {
                     //println!("{} terminated", &proc_id);
-                    self.processes.remove(proc_id);
+                    self.remove_process(proc_id);
                     match e {
                         ProcessExit::Terminate(reason) => self.exit = Some(reason),
                         ProcessExit::Done => {}
@@ -204,10 +641,74 @@ impl Dispatcher {
 
         self.queue.push_back((proc_id, ResumeArg::Empty));
     }
+
+    /// act on what `proc_id` yielded. Returns what to resume it with, or None if it waits.
+    fn yielded(&mut self, proc_id: ProcessKey, y: ProcessYield) -> Option<ResumeArg> {
+        match y {
+            ProcessYield::Send(addr, msg) => {
+                if self.is_traced(proc_id) {
+                    self.emit(proc_id, TraceKind::Send { to: addr, msg: msg.type_name });
+                }
+                self.send_from(Some(Cid(proc_id)), addr, msg);
+            }
+            ProcessYield::SendAfter(delay, addr, msg) => {
+                self.send_after(delay, addr, msg);
+            }
+            ProcessYield::Spawn(coro) => {
+                let cid = self.spawn(coro);
+                self.spawned(proc_id, cid);
+                return Some(ResumeArg::Spawned(cid));
+            }
+            ProcessYield::Spawn2(f) => {
+                let cid = self.spawn2(f);
+                self.spawned(proc_id, cid);
+                return Some(ResumeArg::Spawned(cid));
+            }
+            ProcessYield::SpawnFut(fut) => {
+                self.spawn_fut(fut);
+            }
+            ProcessYield::Monitor(target) => {
+                match self.processes.contains_key(target.0) {
+                    true => self.monitor(Cid(proc_id), target),
+                    false => self.send(Cid(proc_id), Envelope::pack(Down(target)))
+                }
+            }
+            ProcessYield::Empty => {
+                self.set_status(proc_id, Status::Waiting);
+                return None;
+            }
+            ProcessYield::Io => {
+                self.set_status(proc_id, Status::Io);
+                return None;
+            }
+        }
+        Some(ResumeArg::Empty)
+    }
+
+    fn spawned(&mut self, parent: ProcessKey, child: Cid) {
+        if self.is_traced(parent) {
+            self.trace(child, true);
+            self.emit(parent, TraceKind::Spawn { child });
+        }
+    }
+
+    fn set_status(&mut self, proc_id: ProcessKey, status: Status) {
+        if let Some(p) = self.processes.get_mut(proc_id) {
+            p.status = status;
+        }
+    }
     
     fn run_once(&mut self) {
+        if self.sim.is_some() {
+            if let Some((id, arg)) = self.next() {
+                self.run_one(id, arg);
+            }
+            return;
+        }
+
         let mut queue = self.queue2.take().unwrap();
         mem::swap(&mut self.queue, &mut queue);
+        self.metrics.queue_depth.set(queue.len() as f64);
         // self.ready is now empty, ready contains process we need to run
         
         for (id, arg) in queue.drain(..) {
@@ -217,29 +718,227 @@ impl Dispatcher {
         // put empty hashset back
         self.queue2 = Some(queue);
     }
+
+    /// the next entry to run. Random for simulated dispatchers, FIFO otherwise.
+    fn next(&mut self) -> Option<(ProcessKey, ResumeArg)> {
+        match self.sim {
+            Some(ref mut sim) => {
+                for (addr, msg) in sim.poll() {
+                    self.queue.push_back((addr.0, ResumeArg::Message(msg)));
+                }
+                sim.pick(&mut self.queue)
+            }
+            None => self.queue.pop_front()
+        }
+    }
     
     pub fn run(&mut self) -> ExitReason {
         loop {
             // we have CPU work left
-            while self.queue.len() > 0 {
-                self.run_once();
-            }
+            self.run_until_idle();
             
             // a chance to exit here
             if let Some(reason) = self.exit.take() {
                 return reason;
             }
+
+            if self.sim.is_some() {
+                if !self.sim_idle() {
+                    return ExitReason {
+                        code: 0,
+                        msg: "idle"
+                    };
+                }
+                continue;
+            }
+
+            // on virtual time, only the fake reactor can have IO and it never blocks
+            if let Clock::Virtual(_) = self.clock {
+                if self.poll_events(Some(Duration::from_secs(0))) == 0 {
+                    return ExitReason {
+                        code: 0,
+                        msg: "idle"
+                    };
+                }
+                continue;
+            }
             
             // no CPU work left and not exiting, so we have to wait
-            if let Some(sleeper) = self.sleeper {
-                self.run_one(sleeper, ResumeArg::Message(Envelope::pack(Sleep)));
-            } else {
-                return ExitReason {
-                    code: 0,
-                    msg: "no sleeper"
-                };
+            self.idle();
+        }
+    }
+
+    pub fn set_idle_strategy(&mut self, strategy: IdleStrategy) {
+        self.idle = strategy;
+    }
+
+    fn idle(&mut self) {
+        let IdleStrategy { spins, yields } = self.idle;
+        for _ in 0 .. spins {
+            self.metrics.idle_spins.inc();
+            if self.poll_events(Some(Duration::from_secs(0))) > 0 {
+                return;
             }
         }
+        for _ in 0 .. yields {
+            self.metrics.idle_yields.inc();
+            thread::yield_now();
+            if self.poll_events(Some(Duration::from_secs(0))) > 0 {
+                return;
+            }
+        }
+        self.metrics.idle_blocks.inc();
+        self.poll_events(None);
+    }
+}
+
+/// Step-wise API, to drive a dispatcher from another event loop:
+///
+/// ```ignore
+/// loop {
+///     d.run_until_idle();
+///     if let Some(reason) = d.take_exit() { break; }
+///     // wait for d.as_raw_fd() to become readable, at most d.next_timeout()
+///     d.poll_events(Some(Duration::from_secs(0)));
+/// }
+/// ```
+impl Dispatcher {
+    /// run until no process is runnable, without waiting for IO
+    pub fn run_until_idle(&mut self) {
+        let _enter = self.enter();
+        while self.queue.len() > 0 {
+            self.run_once();
+            self.fire_timers();
+        }
+    }
+
+    /// resume at most `max_resumes` processes. Returns how many were resumed.
+    pub fn step(&mut self, max_resumes: usize) -> usize {
+        let _enter = self.enter();
+        self.fire_timers();
+        let mut n = 0;
+        while n < max_resumes {
+            match self.next() {
+                Some((id, arg)) => self.run_one(id, arg),
+                None => break
+            }
+            n += 1;
+        }
+        self.fire_timers();
+        n
+    }
+
+    /// wait up to `timeout` (forever if `None`) for IO or the next timer
+    /// and queue the processes that have to be woken up.
+    /// Returns the number of new entries in the run queue.
+    pub fn poll_events(&mut self, timeout: Option<Duration>) -> usize {
+        let _enter = self.enter();
+        let before = self.queue.len();
+        let timeout = match (timeout, self.next_timeout()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b)
+        };
+
+        if let Some(ref mut sim) = self.sim {
+            for (addr, msg) in sim.poll() {
+                self.queue.push_back((addr.0, ResumeArg::Message(msg)));
+            }
+        } else {
+            self.poll_io(timeout);
+        }
+        self.fire_timers();
+        self.queue.len() - before
+    }
+
+    /// make our reactor and ring the targets of `reactor::register` and the `uring` functions
+    fn enter(&self) -> (reactor::Enter, Option<uring::Enter>, table::Enter) {
+        (reactor::enter(&self.reactor), self.uring.as_ref().map(uring::enter), table::enter(&self.tables))
+    }
+
+    /// wait for IO and queue the messages of the reactor
+    fn poll_io(&mut self, timeout: Option<Duration>) {
+        let mut ready = mem::take(&mut self.ready);
+        let start = Instant::now();
+        match self.reactor.wait(&mut ready, timeout) {
+            Ok(()) => {
+                if let Some(ref mut timeline) = self.timeline {
+                    timeline.span("reactor_wait", None, start, Instant::now());
+                }
+                self.metrics.epoll_wakeups.inc();
+                self.metrics.events_per_wakeup.observe(ready.len() as f64);
+                self.metrics.messages.add(ready.len() as u64);
+
+                for (owner, msg) in ready.drain(..) {
+                    // registrations can outlive their process
+                    if self.processes.contains_key(owner.0) {
+                        self.queue.push_back((owner.0, ResumeArg::Message(msg)));
+                    } else {
+                        debug!("dropping {} for {:?}", msg.type_name, owner);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("reactor wait -> {:?}", e);
+                self.exit = Some(ExitReason {
+                    code: 1,
+                    msg: "reactor failed"
+                });
+            }
+        }
+        self.ready = ready;
+    }
+
+    /// how long the dispatcher may sleep: zero if a process is runnable, `None` if nothing but IO can wake it.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        if self.queue.len() > 0 {
+            return Some(Duration::from_secs(0));
+        }
+        self.timers.next_deadline().map(|t| t.saturating_sub(self.clock.now()))
+    }
+
+    /// the exit reason of a process that requested termination, if any
+    pub fn take_exit(&mut self) -> Option<ExitReason> {
+        self.exit.take()
+    }
+}
+
+impl AsRawFd for Dispatcher {
+    /// the file descriptor of the reactor. It becomes readable when `poll_events` has IO to deliver.
+    fn as_raw_fd(&self) -> RawFd {
+        self.reactor.as_raw_fd()
+    }
+}
+
+impl Dispatcher {
+    /// nothing is runnable in a simulated dispatcher.
+    /// Deliver pending IO or jump to the next timer. Returns false if there is nothing left to do.
+    fn sim_idle(&mut self) -> bool {
+        if let Some(ref mut sim) = self.sim {
+            for (addr, msg) in sim.poll() {
+                self.queue.push_back((addr.0, ResumeArg::Message(msg)));
+            }
+        }
+        if self.queue.len() > 0 {
+            return true;
+        }
+        self.advance_timers(Duration::MAX)
+    }
+
+    /// move a virtual clock to the next timer and fire it, unless it is due after `limit`.
+    /// Returns false if no timer was fired.
+    pub(crate) fn advance_timers(&mut self, limit: Duration) -> bool {
+        match self.timers.next_deadline() {
+            Some(deadline) if deadline <= limit => {
+                self.clock.advance_to(deadline);
+                self.fire_timers();
+                true
+            }
+            _ => false
+        }
+    }
+
+    pub(crate) fn advance_clock(&mut self, t: Duration) {
+        self.clock.advance_to(t);
     }
 }
 
@@ -258,3 +957,99 @@ impl Wake for DispatchWaker {
         self.tx.send(self.key).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::prelude::*;
+    use crate::sys::Errno;
+    use crate::testing::TestDispatcher;
+
+    /// adds what its setup returned to each number, like a server that may fail to set up
+    fn adder(setup: Result<u32, Errno>, probe: Cid) -> GenBox {
+        Box::pin(Box::new(move |_: ResumeArg| {
+            let n = match setup {
+                Ok(n) => n,
+                Err(_) => done!()
+            };
+            loop {
+                recv!{ u32, m => send!(probe, m + n) }
+            }
+        }))
+    }
+
+    #[test]
+    fn process_can_end_in_its_first_resume() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let failed = d.spawn(adder(Err(libc::EMFILE as Errno), probe.cid()));
+        assert!(d.process_info(failed).is_none());
+        d.send(failed, Envelope::pack(1u32));
+        d.expect_no_message(&probe, Duration::from_secs(1));
+
+        let ok = d.spawn(adder(Ok(10), probe.cid()));
+        d.send(ok, Envelope::pack(1u32));
+        assert_eq!(d.expect_message::<u32>(&probe, Duration::from_secs(1)), 11);
+    }
+
+    #[test]
+    fn messages_wait_until_a_send_is_done() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let p = probe.cid();
+        let echo = d.spawn(dispatcher!{ u32, n => send!(p, n) });
+        // both are queued before `echo` runs, so the second arrives while the first is being sent
+        d.send(echo, Envelope::pack(1u32));
+        d.send(echo, Envelope::pack(2u32));
+        assert_eq!(d.expect_message::<u32>(&probe, Duration::from_secs(1)), 1);
+        assert_eq!(d.expect_message::<u32>(&probe, Duration::from_secs(1)), 2);
+    }
+
+    #[test]
+    fn monitors_are_deduplicated_and_removed_with_the_watcher() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let p = probe.cid();
+        let watcher = d.spawn(dispatcher!{ Down, down => send!(p, down) });
+        let target = d.spawn(dispatcher!{ u32, _ => done!() });
+        d.monitor(watcher, target);
+        d.monitor(watcher, target);
+        assert_eq!(d.process_info(target).unwrap().monitors, [watcher]);
+
+        d.send(target, Envelope::pack(0u32));
+        assert_eq!(d.expect_message::<Down>(&probe, Duration::from_secs(1)).0, target);
+        d.expect_no_message(&probe, Duration::from_secs(1));
+
+        let target = d.spawn(dispatcher!{ u32, _ => done!() });
+        let watcher = d.spawn(dispatcher!{ u32, _ => done!() });
+        d.monitor(watcher, target);
+        d.send(watcher, Envelope::pack(0u32));
+        d.run_until_idle();
+        assert!(d.process_info(target).unwrap().monitors.is_empty());
+    }
+
+    /// monitors `target` before taking any message, and reports its `Down` to `probe`
+    fn watch(target: Cid, probe: Cid) -> GenBox {
+        Box::pin(Box::new(move |_: ResumeArg| {
+            monitor!(target);
+            recv!{ Down, down => send!(probe, down) }
+            ProcessExit::Done
+        }))
+    }
+
+    #[test]
+    fn a_process_can_monitor_before_its_first_message() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let p = probe.cid();
+        let target = d.spawn(dispatcher!{ u32, _ => done!() });
+        let watcher = d.spawn(watch(target, p));
+        assert_eq!(d.process_info(target).unwrap().monitors, [watcher]);
+        d.send(target, Envelope::pack(0u32));
+        assert_eq!(d.expect_message::<Down>(&probe, Duration::from_secs(1)).0, target);
+
+        // already gone
+        d.spawn(watch(target, p));
+        assert_eq!(d.expect_message::<Down>(&probe, Duration::from_secs(1)).0, target);
+    }
+}
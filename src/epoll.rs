@@ -1,106 +1,141 @@
 use std::os::unix::io::{RawFd, AsRawFd};
-use crate::sys::{epoll::Event, *};
-use std::ops::Deref;
-use crate::dispatch::{Dispatcher, GenBox, Sleep, Cid, ProcessYield, ResumeArg};
+use crate::sys::*;
+use std::time::{Duration, Instant};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use crate::dispatch::Cid;
 use crate::message::Envelope;
-use slotmap::KeyData;
+use crate::reactor::{Reactor, read_counter};
+
+pub use crate::reactor::{Token, WakeUp, Registered, register};
+
+const EINTR: Errno = libc::EINTR as Errno;
+
+/// initial size of the event buffer. It grows up to `MAX_EVENTS` when it fills up.
+const MIN_EVENTS: usize = 64;
+const MAX_EVENTS: usize = 64 * 1024;
 
 pub struct EPoll {
-    fd: RawFd
-}
+    fd: RawFd,
 
-thread_local! {
-    static POLL: EPoll = EPoll::new();
+    /// owner of each registration, and the fd of counter registrations
+    tokens: RefCell<HashMap<Token, (Cid, Option<RawFd>)>>,
+    next_token: Cell<u64>,
+    events: RefCell<Vec<epoll::Event>>,
+    woken: Cell<bool>,
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct WakeUp(epoll::Flags);
-
 impl EPoll {
     pub fn new() -> EPoll {
         let fd = unsafe { epoll::epoll_create() }.unwrap();
-        EPoll { fd }
-    }
-    fn add(&self, fd: RawFd, event: epoll::Event) {
-        unsafe {
-            epoll::epoll_ctl(self.fd, epoll::CtlOp::Add, fd, Some(&event)).expect("epoll_ctl");
+        EPoll {
+            fd,
+            tokens: RefCell::new(HashMap::new()),
+            next_token: Cell::new(1),
+            events: RefCell::new(Vec::with_capacity(MIN_EVENTS)),
+            woken: Cell::new(false),
         }
     }
-    fn remove(&self, fd: RawFd) {
+    fn add(&self, fd: RawFd, owner: Cid, flags: epoll::Flags, counter: Option<RawFd>) -> Result<Token, Errno> {
+        let token = Token(self.next_token.get());
+        let event = epoll::Event { events: flags, data: token.0 };
         unsafe {
-            epoll::epoll_ctl(self.fd, epoll::CtlOp::Del, fd, None).expect("epoll_ctl");
+            epoll::epoll_ctl(self.fd, epoll::CtlOp::Add, fd, Some(&event))?;
         }
+        self.next_token.set(token.0 + 1);
+        self.tokens.borrow_mut().insert(token, (owner, counter));
+        Ok(token)
     }
-    fn wait(&self, set: &mut Vec<epoll::Event>) -> Result<(), Errno> {
-        if set.capacity() < 10 {
-            set.reserve(10);
-        }
-        unsafe {
-            match epoll::epoll_wait(self.fd, set.as_mut_ptr(), set.capacity(), -1) {
-                Ok(n) => {
-                    set.set_len(n);
-                    Ok(())
-                },
-                Err(e) => {
-                    set.set_len(0);
-                    Err(e)
+    /// wait up to `timeout` (forever if None) and fill `set` with the events.
+    /// Retries when interrupted by a signal.
+    fn wait_events(&self, set: &mut Vec<epoll::Event>, timeout: Option<Duration>) -> Result<(), Errno> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            // round up, so we don't wake up before the timer is due
+            let timeout = match deadline {
+                Some(d) => {
+                    let t = d.saturating_duration_since(Instant::now());
+                    ((t.as_nanos() + 999_999) / 1_000_000).min(i32::MAX as u128) as i32
+                }
+                None => -1
+            };
+            unsafe {
+                match epoll::epoll_wait(self.fd, set.as_mut_ptr(), set.capacity(), timeout) {
+                    Ok(n) => {
+                        set.set_len(n);
+                        return Ok(());
+                    },
+                    Err(EINTR) => continue,
+                    Err(e) => {
+                        set.set_len(0);
+                        return Err(e);
+                    }
                 }
             }
         }
     }
 }
-impl Drop for EPoll {
-    fn drop(&mut self) {
+impl Reactor for EPoll {
+    fn register(&self, fd: RawFd, owner: Cid, flags: epoll::Flags) -> Result<Token, Errno> {
+        self.add(fd, owner, flags, None)
+    }
+    fn register_counter(&self, fd: RawFd, owner: Cid) -> Result<Token, Errno> {
+        self.add(fd, owner, epoll::Flags::In, Some(fd))
+    }
+    fn modify(&self, fd: RawFd, token: Token, flags: epoll::Flags) -> Result<(), Errno> {
+        let event = epoll::Event { events: flags, data: token.0 };
         unsafe {
-            let _ = close(self.fd);
+            epoll::epoll_ctl(self.fd, epoll::CtlOp::Mod, fd, Some(&event))
         }
     }
-}
+    fn deregister(&self, fd: RawFd, token: Token) -> Result<(), Errno> {
+        self.tokens.borrow_mut().remove(&token);
+        unsafe {
+            epoll::epoll_ctl(self.fd, epoll::CtlOp::Del, fd, None)
+        }
+    }
+    fn wait(&self, ready: &mut Vec<(Cid, Envelope)>, mut timeout: Option<Duration>) -> Result<(), Errno> {
+        if self.woken.replace(false) {
+            timeout = Some(Duration::from_secs(0));
+        }
+        let mut events = self.events.borrow_mut();
+        self.wait_events(&mut events, timeout)?;
 
-pub struct Registered<F: AsRawFd> {
-    inner: F
-}
-impl<F: AsRawFd> Deref for Registered<F> {
-    type Target = F;
-    fn deref(&self) -> &F {
-        &self.inner
+        let tokens = self.tokens.borrow();
+        for i in 0 .. events.len() {
+            let epoll::Event { events: flags, data } = events[i];
+            let token = Token(data);
+            // the registration may have been removed since epoll_wait returned
+            match tokens.get(&token) {
+                Some(&(owner, None)) => ready.push((owner, Envelope::pack(WakeUp::new(token, flags)))),
+                Some(&(owner, Some(fd))) => if let Some(count) = read_counter(fd) {
+                    ready.push((owner, Envelope::pack(WakeUp::with_count(token, flags, count))));
+                },
+                None => debug!("dropping IO event for {:?}", token)
+            }
+        }
+
+        // a full buffer means more events are probably waiting
+        if events.len() == events.capacity() && events.capacity() < MAX_EVENTS {
+            let n = events.capacity();
+            events.reserve(n);
+        }
+        events.clear();
+        Ok(())
     }
-}
-impl<F: AsRawFd> Registered<F> {
-    pub fn unregister(self) -> F {
-        let fd = self.inner.as_raw_fd();
-        POLL.with(|p| p.remove(fd));
-        self.inner
+    fn wake(&self) {
+        self.woken.set(true);
     }
 }
-
-pub fn register<F: AsRawFd>(f: F, event: epoll::Event) -> Registered<F> {
-    let fd = f.as_raw_fd();
-    POLL.with(|p| p.add(fd, event));
-    Registered { inner: f }
+impl AsRawFd for EPoll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
 }
-
-pub fn sleeper() -> GenBox {
-    Box::pin(Box::new(move |_: ResumeArg| {
-        let mut events = Vec::with_capacity(1024);
-        loop {
-            recv!{
-                Sleep, _ => {
-                    match POLL.with(|p| p.wait(&mut events)) {
-                        Ok(()) => {
-                            for i in 0 .. events.len() {
-                                let epoll::Event { events, data } = events[i];
-                                send!(Cid::from_ffi(data), WakeUp(events));
-                            }
-                            events.clear();
-                        },
-                        Err(e) => {
-                            eprintln!("epoll::wait -> {:?}", e);
-                            exit!(1, "epoll failed");
-                        }
-                    }
-                }
-            }
+impl Drop for EPoll {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = close(self.fd);
         }
-    }))
+    }
 }
@@ -1,13 +1,15 @@
 //! EventFd binding
 //!
-//! This crate implements a simple binding for Linux eventfd(). See
-//! eventfd(2) for specific details of behaviour.
+//! A simple binding for Linux eventfd(). See eventfd(2) for specific details of behaviour.
+//! An `EventFd` can be registered with `reactor::register_counter`, so its owner gets a `WakeUp`
+//! carrying the counter. `Notifier` uses that to let other threads wake a process.
 
-use libc;
+use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::mem;
-use syscall_alt::syscalls::{syscall2};
-use syscall_alt::constants::SYS::SYS_eventfd2;
+use std::sync::Arc;
+use crate::sys::{self, Errno};
+
+pub use crate::sys::eventfd::Flags;
 
 #[derive(Debug)]
 pub enum EventFdError {
@@ -15,7 +17,44 @@ pub enum EventFdError {
     TooManyOpenFilesInProcess,
     TooManyOpenFilesInSystem,
     KernelOutOfMemory,
-    KernelError
+    KernelError,
+
+    /// non-blocking mode only: nothing to read, or the write would overflow the counter
+    WouldBlock,
+    Other(Errno)
+}
+impl From<Errno> for EventFdError {
+    fn from(e: Errno) -> EventFdError {
+        match e as i32 {
+            libc::EINVAL => EventFdError::InvalidFlags,
+            libc::EMFILE => EventFdError::TooManyOpenFilesInProcess,
+            libc::ENFILE => EventFdError::TooManyOpenFilesInSystem,
+            libc::ENOMEM => EventFdError::KernelOutOfMemory,
+            libc::ENODEV => EventFdError::KernelError,
+            libc::EAGAIN => EventFdError::WouldBlock,
+            _ => EventFdError::Other(e)
+        }
+    }
+}
+
+impl EventFdError {
+    /// the error number this was made from
+    pub fn errno(&self) -> Errno {
+        (match *self {
+            EventFdError::InvalidFlags => libc::EINVAL,
+            EventFdError::TooManyOpenFilesInProcess => libc::EMFILE,
+            EventFdError::TooManyOpenFilesInSystem => libc::ENFILE,
+            EventFdError::KernelOutOfMemory => libc::ENOMEM,
+            EventFdError::KernelError => libc::ENODEV,
+            EventFdError::WouldBlock => libc::EAGAIN,
+            EventFdError::Other(e) => return e
+        }) as Errno
+    }
+}
+impl From<EventFdError> for io::Error {
+    fn from(e: EventFdError) -> io::Error {
+        io::Error::from_raw_os_error(e.errno() as i32)
+    }
 }
 
 pub struct EventFd {
@@ -23,45 +62,26 @@ pub struct EventFd {
 }
 
 impl EventFd {
-    /// Create a new EventFd. Flags is the bitwise OR of EFD_* constants, or 0 for no flags.
-    /// The underlying file descriptor is closed when the EventFd instance's lifetime ends.
-    ///
-    /// TODO: work out how to integrate this FD into the wider world
-    /// of fds. There's currently no way to poll/select on the fd.
-    pub fn new(initval: usize, flags: i32) -> Result<EventFd, EventFdError> {
-        let res = unsafe {
-            syscall2(SYS_eventfd2, initval as isize, flags as isize)
-        };
-        match res as i32 {
-            fd if fd > 0 => Ok(EventFd { fd: fd }),
-            libc::EINVAL => Err(EventFdError::InvalidFlags),
-            libc::EMFILE => Err(EventFdError::TooManyOpenFilesInProcess),
-            libc::ENFILE => Err(EventFdError::TooManyOpenFilesInSystem),
-            libc::ENOMEM => Err(EventFdError::KernelOutOfMemory),
-            libc::ENODEV => Err(EventFdError::KernelError),
-            e => panic!("error code: {:?}", e)
-        }
+    /// Create a new EventFd. The underlying file descriptor is closed when the EventFd instance's lifetime ends.
+    pub fn new(initval: u32, flags: Flags) -> Result<EventFd, EventFdError> {
+        let fd = unsafe { sys::eventfd::eventfd(initval, flags)? };
+        Ok(EventFd { fd })
     }
 
-    /// Read the current value of the eventfd. This will block until
-    /// the value is non-zero. In semaphore mode this will only ever
+    /// Read the current value of the eventfd. This blocks until the value is non-zero,
+    /// or fails with `WouldBlock` in non-blocking mode. In semaphore mode this will only ever
     /// decrement the count by 1 and return 1; otherwise it atomically
     /// returns the current value and sets it to zero.
     pub fn read(&self) -> Result<u64, EventFdError> {
         let mut buf = [0u8; 8];
-        unsafe {
-            libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
-        }
-        let val = unsafe { mem::transmute(buf) };
-        Ok(val)
+        unsafe { sys::read(self.fd, &mut buf)? };
+        Ok(u64::from_ne_bytes(buf))
     }
 
-    /// Add to the current value. Blocks if the value would wrap u64.
+    /// Add to the current value. Blocks if the value would wrap u64,
+    /// or fails with `WouldBlock` in non-blocking mode.
     pub fn write(&self, val: u64) -> Result<(), EventFdError> {
-        let buf: [u8; 8] = unsafe { mem::transmute(val) };
-        unsafe {
-            libc::write(self.fd, buf.as_ptr() as *mut libc::c_void, buf.len());
-        }
+        unsafe { sys::write(self.fd, &val.to_ne_bytes())? };
         Ok(())
     }
 }
@@ -77,8 +97,51 @@ impl AsRawFd for EventFd {
 impl Drop for EventFd {
     fn drop(&mut self) {
         unsafe {
-            libc::close(self.fd);
+            let _ = sys::close(self.fd);
         }
     }
 }
 
+/// an eventfd shared by a process and the threads that wake it.
+/// The process registers one clone with `reactor::register_counter`,
+/// and each `WakeUp` counts the notifications since the last one.
+///
+/// ```ignore
+/// let notifier = Notifier::new()?;
+/// let remote = notifier.clone();
+/// thread::spawn(move || remote.notify());
+/// d.spawn2(Box::new(move |id| {
+///     let notifier = reactor::register_counter(notifier, id);
+///     Box::pin(Box::new(move |_: ResumeArg| {
+///         // moved in here, the registration lives as long as the process
+///         let _notifier = match notifier {
+///             Ok(notifier) => notifier,
+///             Err(e) => {
+///                 error!("register -> {}", e);
+///                 done!();
+///             }
+///         };
+///         loop {
+///             recv!{ WakeUp, w => println!("notified {} times", w.count()) }
+///         }
+///     }))
+/// }));
+/// ```
+#[derive(Clone)]
+pub struct Notifier(Arc<EventFd>);
+impl Notifier {
+    pub fn new() -> Result<Notifier, EventFdError> {
+        let efd = EventFd::new(0, Flags::NonBlock | Flags::CloseOnExec)?;
+        Ok(Notifier(Arc::new(efd)))
+    }
+
+    /// wake the process that registered this notifier. Can be called from any thread.
+    pub fn notify(&self) -> Result<(), EventFdError> {
+        self.0.write(1)
+    }
+}
+impl AsRawFd for Notifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
@@ -1,20 +1,37 @@
-use std::ffi::{OsStr, CString};
+//! File system access that does not block the dispatcher
+//!
+//! ```ignore
+//! let fs = spawn!(|id| fs::server(id, 4));
+//! send!(fs, ReadFile { path: "/etc/hostname".into(), reply: me });
+//! // me receives Contents { path, result: Ok(data) }
+//! ```
+//!
+//! Disk I/O blocks, whatever epoll says about a file. The server hands each request to a pool of
+//! blocking threads, the dirty schedulers, and sends the results back from the dispatcher thread.
+//!
+//! Directory handles are capabilities: everything opened through one stays below it.
+//!
+//! ```ignore
+//! let tenants = OwnedDirectory::open("/srv/tenants")?;
+//! let home = tenants.directory("42")?;
+//! spawn!(|id| tenant(id, home));
+//! // in the tenant, home.file("../41/data", O_RDONLY) fails with OpenError::AccessNotAllowed
+//! ```
+
+use std::ffi::{OsString, CString};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::Path;
-use std::rc::Rc;
 use std::os::unix::ffi::OsStrExt;
-use std::{mem, fmt};
-use libc;
-use syscall_alt::syscalls::{syscall1, syscall2, syscall3};
-use syscall_alt::constants::SYS::*;
-
-use common::*;
-use io::ReadError;
-use mem::Slice;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{fmt, fs, io, mem, thread};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use crate::prelude::*;
+use crate::reactor::{self, WakeUp, Registered};
+use crate::eventfd::Notifier;
+use crate::sys::{self, Errno, fs::{OpenHow, O_DIRECTORY, O_PATH, RESOLVE_BENEATH, RESOLVE_NO_MAGICLINKS}};
 
-const O_DIRECT:     i32 = 0o00040000;
-const O_DIRECTORY:  i32 = 0o00200000;
-const O_PATH:       i32 = 0o10000000;
+pub use crate::sys::fs::O_DIRECT;
+pub use libc::{O_RDONLY, O_WRONLY, O_RDWR, O_APPEND, O_TRUNC, O_EXCL};
 
 #[derive(Debug)]
 pub enum OpenError {
@@ -31,25 +48,52 @@ pub enum OpenError {
     NoSpaceLeftOnDevice,
     PathComponentNonDirectory,
     PermissionDenied,
-    FilesystemReadOnly
+    FilesystemReadOnly,
+
+    /// any other error of the open call
+    Other(Errno)
 }
 
+/// a directory to open files and directories in, which the paths cannot escape
 pub struct OwnedDirectory {
-    dirfd:  u32
+    dirfd:  RawFd
 }
 impl OwnedDirectory {
-    pub fn open(path: &OsStr) -> Result<OwnedDirectory, OpenError> {
-        let fd = open(&path, O_DIRECTORY | O_PATH)?;
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<OwnedDirectory, OpenError> {
+        let fd = open(path.as_ref(), O_DIRECTORY | O_PATH)?;
         Ok(OwnedDirectory { dirfd: fd })
     }
-    pub fn file(&self, name: &OsStr) -> Result<SharedFile, OpenError> {
-        let fd = openat(self.dirfd, name, O_DIRECT)?;
-        Ok(SharedFile::from_fd(fd))
+    /// open `name` with `flags`, like `O_RDONLY` or `O_RDWR | O_DIRECT`.
+    /// Files it creates have mode 0o666, less the umask.
+    pub fn file<P: AsRef<Path>>(&self, name: P, flags: i32) -> Result<File, OpenError> {
+        let fd = openat(self.dirfd, name.as_ref(), flags, 0o666)?;
+        unsafe { File::from_fd(fd) }
     }
-    pub fn directory(&self, name: &OsStr) -> Result<OwnedDirectory, OpenError> {
-        let fd = openat(self.dirfd, name, O_DIRECTORY | O_PATH)?;
+    /// like `file`, but creates `name` if it does not exist
+    pub fn create<P: AsRef<Path>>(&self, name: P, flags: i32) -> Result<File, OpenError> {
+        self.file(name, flags | libc::O_CREAT)
+    }
+    pub fn directory<P: AsRef<Path>>(&self, name: P) -> Result<OwnedDirectory, OpenError> {
+        let fd = openat(self.dirfd, name.as_ref(), O_DIRECTORY | O_PATH, 0)?;
         Ok(OwnedDirectory { dirfd: fd })
     }
+    /// another handle to the same directory
+    pub fn try_clone(&self) -> Result<OwnedDirectory, OpenError> {
+        match unsafe { sys::fs::dup(self.dirfd) } {
+            Ok(fd) => Ok(OwnedDirectory { dirfd: fd }),
+            Err(e) => Err(open_error(e, Path::new(".")))
+        }
+    }
+}
+impl AsRawFd for OwnedDirectory {
+    fn as_raw_fd(&self) -> RawFd {
+        self.dirfd
+    }
+}
+impl fmt::Debug for OwnedDirectory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OwnedDirectory {{ dirfd: {} }}", self.dirfd)
+    }
 }
 impl Drop for OwnedDirectory {
     fn drop(&mut self) {
@@ -58,53 +102,44 @@ impl Drop for OwnedDirectory {
         }
     }
 }
-impl AsyncDirectory for OwnedDirectory {
-    type File = SharedFile;
-    type Error = OpenError;
-    
-    fn get_directory(&self, path: &str) -> Box<Future<Item=Self, Error=Self::Error>> {
-        box future::result(self.directory(&OsStr::new(path)))
-    }
-    fn get_file(&self, path: &str) -> Box<Future<Item=Self::File, Error=Self::Error>> {
-        box future::result(self.file(&OsStr::new(path)))
-    }
-}
 
-#[derive(Clone)]
+/// an `OwnedDirectory` that can be cloned, and sent to other threads
+#[derive(Clone, Debug)]
 pub struct SharedDirectory {
-    inner: Rc<OwnedDirectory>
+    inner: Arc<OwnedDirectory>
 }
-impl AsyncDirectory for SharedDirectory {
-    type File = SharedFile;
-    type Error = OpenError;
-    
-    fn get_directory(&self, path: &str) -> Box<Future<Item=Self, Error=Self::Error>> {
-        box self.inner.get_directory(path)
-        .map(|dir| SharedDirectory { inner: Rc::new(dir) })
+impl SharedDirectory {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SharedDirectory, OpenError> {
+        OwnedDirectory::open(path).map(SharedDirectory::from)
     }
-    fn get_file(&self, path: &str) -> Box<Future<Item=Self::File, Error=Self::Error>> {
-        self.inner.get_file(path)
+    pub fn file<P: AsRef<Path>>(&self, name: P, flags: i32) -> Result<File, OpenError> {
+        self.inner.file(name, flags)
+    }
+    pub fn create<P: AsRef<Path>>(&self, name: P, flags: i32) -> Result<File, OpenError> {
+        self.inner.create(name, flags)
+    }
+    pub fn directory<P: AsRef<Path>>(&self, name: P) -> Result<SharedDirectory, OpenError> {
+        self.inner.directory(name).map(SharedDirectory::from)
     }
 }
-
-impl AsyncOpen for SharedDirectory {
-    type Error = OpenError;
-    fn open(name: &str) -> Box<Future<Item=Self, Error=Self::Error>> {
-        box future::result(
-            OwnedDirectory::open(name.as_ref()).map(|dir|
-                SharedDirectory { inner: Rc::new(dir) }
-            )
-        )
+impl From<OwnedDirectory> for SharedDirectory {
+    fn from(dir: OwnedDirectory) -> SharedDirectory {
+        SharedDirectory { inner: Arc::new(dir) }
+    }
+}
+impl AsRawFd for SharedDirectory {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.dirfd
     }
 }
 
 pub struct File {
-    fd:     u32,
+    fd:     RawFd,
     stat:   libc::stat
 }
 impl fmt::Debug for File {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "OwnedFile {{ fd: {}, stat: ... }}", self.fd)
+        write!(f, "File {{ fd: {}, stat: ... }}", self.fd)
     }
 }
 
@@ -112,105 +147,268 @@ impl AsRawFd for File {
     /// Return the raw underlying fd. The caller must make sure self's
     /// lifetime is longer than any users of the fd.
     fn as_raw_fd(&self) -> RawFd {
-        self.fd as RawFd
+        self.fd
     }
 }
 
-fn open(pathname: &OsStr, flags: i32) -> Result<u32, OpenError>
-{
-    let cname = CString::new(pathname.as_bytes()).unwrap();
-    let res = unsafe {
-        syscall2(SYS_open, cname.as_ptr() as isize, flags as isize)
-    };
-    if res >= 0 {
-        assert!(res <= ::std::u32::MAX as isize);
-        Ok(res as u32)
-    } else {
-        let name = cname.into_string().unwrap_or_else(|_| "<invalid utf-8>".into());
-        match -res as i32 {
-            libc::EACCES => Err(OpenError::AccessNotAllowed),
-            libc::EEXIST => Err(OpenError::FileAlreadyExists(name)),
-            libc::EINVAL => Err(OpenError::FilesystemUnsupported),
-            libc::EISDIR => Err(OpenError::IsDirectory(name)),
-            libc::ELOOP => Err(OpenError::TooManySymlinks),
-            libc::EMFILE => Err(OpenError::TooManyOpenFilesInProcess),
-            libc::ENFILE => Err(OpenError::TooManyOpenFilesInSystem),
-            libc::ENAMETOOLONG => Err(OpenError::NameTooLong),
-            libc::ENOENT => Err(OpenError::DoesNotExist(name)),
-            libc::ENOMEM => Err(OpenError::KernelOutOfMemory),
-            libc::ENOSPC => Err(OpenError::NoSpaceLeftOnDevice),
-            libc::ENOTDIR => Err(OpenError::PathComponentNonDirectory),
-            libc::EPERM => Err(OpenError::PermissionDenied),
-            libc::EROFS => Err(OpenError::FilesystemReadOnly),
-            e => panic!("error code: {}", e)
-        }
+fn open_error(errno: Errno, name: &Path) -> OpenError {
+    let name = name.to_string_lossy().into_owned();
+    match errno as i32 {
+        libc::EACCES => OpenError::AccessNotAllowed,
+        libc::EEXIST => OpenError::FileAlreadyExists(name),
+        libc::EINVAL => OpenError::FilesystemUnsupported,
+        libc::EISDIR => OpenError::IsDirectory(name),
+        libc::ELOOP => OpenError::TooManySymlinks,
+        libc::EMFILE => OpenError::TooManyOpenFilesInProcess,
+        libc::ENFILE => OpenError::TooManyOpenFilesInSystem,
+        libc::ENAMETOOLONG => OpenError::NameTooLong,
+        libc::ENOENT => OpenError::DoesNotExist(name),
+        libc::ENOMEM => OpenError::KernelOutOfMemory,
+        libc::ENOSPC => OpenError::NoSpaceLeftOnDevice,
+        libc::ENOTDIR => OpenError::PathComponentNonDirectory,
+        libc::EPERM => OpenError::PermissionDenied,
+        libc::EROFS => OpenError::FilesystemReadOnly,
+        libc::EDQUOT => OpenError::NoSpaceLeftOnDevice,
+        // without openat2, or with flags the file system can't do
+        libc::ENOSYS | libc::E2BIG | libc::EOPNOTSUPP | libc::ENODEV | libc::ENXIO
+            | libc::EFBIG | libc::EOVERFLOW => OpenError::FilesystemUnsupported,
+        // RESOLVE_BENEATH: the path would leave the directory it is opened in
+        libc::EXDEV => OpenError::AccessNotAllowed,
+        _ => OpenError::Other(errno)
     }
 }
 
-fn openat(dirfd: u32, pathname: &OsStr, flags: i32) -> Result<u32, OpenError>
+fn open(path: &Path, flags: i32) -> Result<RawFd, OpenError>
 {
-    let cname = CString::new(pathname.as_bytes()).unwrap();
-    let flags = flags | O_DIRECT;
-    let res = unsafe {
-        syscall3(SYS_openat, dirfd as isize, cname.as_ptr() as isize, flags as isize)
-    };
-    if res >= 0 {
-        assert!(res <= ::std::u32::MAX as isize);
-        Ok(res as u32)
-    } else {
-        let name = cname.into_string().unwrap_or_else(|_| "<invalid utf-8>".into());
-        match -res as i32 {
-            libc::EACCES => Err(OpenError::AccessNotAllowed),
-            libc::EEXIST => Err(OpenError::FileAlreadyExists(name)),
-            libc::EINVAL => Err(OpenError::FilesystemUnsupported),
-            libc::EISDIR => Err(OpenError::IsDirectory(name)),
-            libc::ELOOP => Err(OpenError::TooManySymlinks),
-            libc::EMFILE => Err(OpenError::TooManyOpenFilesInProcess),
-            libc::ENFILE => Err(OpenError::TooManyOpenFilesInSystem),
-            libc::ENAMETOOLONG => Err(OpenError::NameTooLong),
-            libc::ENOENT => Err(OpenError::DoesNotExist(name)),
-            libc::ENOMEM => Err(OpenError::KernelOutOfMemory),
-            libc::ENOSPC => Err(OpenError::NoSpaceLeftOnDevice),
-            libc::ENOTDIR => Err(OpenError::PathComponentNonDirectory),
-            libc::EPERM => Err(OpenError::PermissionDenied),
-            libc::EROFS => Err(OpenError::FilesystemReadOnly),
-            e => panic!("error code: {}", e)
-        }
-    }
+    let cname = CString::new(path.as_os_str().as_bytes())
+        // no file has a NUL byte in its name
+        .map_err(|_| OpenError::DoesNotExist(path.to_string_lossy().into_owned()))?;
+    unsafe { sys::fs::open(&cname, flags, 0o666) }.map_err(|e| open_error(e, path))
 }
 
-unsafe fn close(fd: u32) {
-    let ret = syscall1(SYS_close, fd as isize);
-    assert_eq!(ret, 0);
+/// open `path` below `dirfd`. It may not be absolute, and neither `..` nor symlinks may lead out of `dirfd`.
+fn openat(dirfd: RawFd, path: &Path, flags: i32, mode: u32) -> Result<RawFd, OpenError>
+{
+    let cname = CString::new(path.as_os_str().as_bytes())
+        // no file has a NUL byte in its name
+        .map_err(|_| OpenError::DoesNotExist(path.to_string_lossy().into_owned()))?;
+    let how = OpenHow {
+        flags: (flags | libc::O_CLOEXEC) as u64,
+        mode: mode as u64,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS
+    };
+    unsafe { sys::fs::openat2(dirfd, &cname, &how) }.map_err(|e| open_error(e, path))
 }
 
-/// fd has to be a valid file descriptor.
-/// will panic if unsuccessful.
-fn stat(fd: u32) -> libc::stat {
-    let mut stat: libc::stat = unsafe { mem::zeroed() };
-    let res = unsafe {
-        syscall2(SYS_fstat, fd as isize, &mut stat as *mut libc::stat as isize)
-    };
-    if res == 0 {
-        stat
-    } else {
-        panic!("stat({}) -> {}", fd, -res);
-    }
+unsafe fn close(fd: RawFd) {
+    let _ = sys::close(fd);
 }
 
 impl File {
-    unsafe fn from_fd(fd: u32) -> File {
-        File { fd: fd, stat: stat(fd) }
+    /// takes ownership of `fd`, which is closed if it fails
+    unsafe fn from_fd(fd: RawFd) -> Result<File, OpenError> {
+        let mut stat: libc::stat = mem::zeroed();
+        match sys::fs::fstat(fd, &mut stat) {
+            Ok(()) => Ok(File { fd, stat }),
+            Err(e) => {
+                close(fd);
+                Err(open_error(e, Path::new("")))
+            }
+        }
     }
     pub fn open<P: AsRef<Path>>(path: P) -> Result<File, OpenError> {
-        let fd = open(path.as_ref().as_os_str(), O_DIRECT)?;
-        let file = unsafe { File::from_fd(fd) };
-        Ok(file)
+        let fd = open(path.as_ref(), O_DIRECT)?;
+        unsafe { File::from_fd(fd) }
+    }
+    /// open for reading and writing, creating the file if it does not exist
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<File, OpenError> {
+        let fd = open(path.as_ref(), O_DIRECT | libc::O_RDWR | libc::O_CREAT)?;
+        unsafe { File::from_fd(fd) }
+    }
+    pub fn size(&self) -> u64 {
+        self.stat.st_size as u64
     }
 }
-impl Drop for OwnedFile {
+impl Drop for File {
     fn drop(&mut self) {
         unsafe { close(self.fd) }
     }
 }
 
+/// read a whole file, answered with `Contents`
+#[derive(Debug)]
+pub struct ReadFile {
+    pub path: PathBuf,
+    pub reply: Cid,
+}
+
+/// create or replace a file, answered with `Written`
+#[derive(Debug)]
+pub struct WriteFile {
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+    pub reply: Cid,
+}
+
+/// answered with `Stats`
+#[derive(Debug)]
+pub struct Stat {
+    pub path: PathBuf,
+    pub reply: Cid,
+}
+
+/// list a directory, answered with `Entries`
+#[derive(Debug)]
+pub struct ReadDir {
+    pub path: PathBuf,
+    pub reply: Cid,
+}
+
+#[derive(Debug)]
+pub struct Contents {
+    pub path: PathBuf,
+    pub result: io::Result<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct Written {
+    pub path: PathBuf,
+    pub result: io::Result<()>,
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    pub path: PathBuf,
+    pub result: io::Result<fs::Metadata>,
+}
+
+#[derive(Debug)]
+pub struct Entries {
+    pub path: PathBuf,
+    pub result: io::Result<Vec<Entry>>,
+}
+
+/// a directory entry, without `.` and `..`
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: OsString,
+    pub file_type: fs::FileType,
+}
+
+enum Job {
+    Read(PathBuf),
+    Write(PathBuf, Vec<u8>),
+    Stat(PathBuf),
+    ReadDir(PathBuf),
+}
+impl Job {
+    /// runs on a dirty scheduler
+    fn run(self) -> Done {
+        match self {
+            Job::Read(path) => {
+                let result = fs::read(&path);
+                Done::Read(Contents { path, result })
+            }
+            Job::Write(path, data) => {
+                let result = fs::write(&path, data);
+                Done::Write(Written { path, result })
+            }
+            Job::Stat(path) => {
+                let result = fs::metadata(&path);
+                Done::Stat(Stats { path, result })
+            }
+            Job::ReadDir(path) => {
+                let result = fs::read_dir(&path).and_then(|entries| {
+                    entries.map(|entry| {
+                        let entry = entry?;
+                        Ok(Entry { name: entry.file_name(), file_type: entry.file_type()? })
+                    }).collect()
+                });
+                Done::ReadDir(Entries { path, result })
+            }
+        }
+    }
+}
+
+enum Done {
+    Read(Contents),
+    Write(Written),
+    Stat(Stats),
+    ReadDir(Entries),
+}
+
+/// the dirty schedulers. They stop once the pool is dropped and their current job is done.
+struct Pool {
+    jobs: Sender<(Cid, Job)>,
+    done: Receiver<(Cid, Done)>,
+
+    /// notified by the threads for every finished job
+    notifier: Registered<Notifier>,
+}
+impl Pool {
+    fn new(id: Cid, threads: usize) -> io::Result<Pool> {
+        let notifier = Notifier::new()?;
+        let registered = reactor::register_counter(notifier.clone(), id)
+            .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+        let (jobs, job_rx) = unbounded::<(Cid, Job)>();
+        let (done_tx, done) = unbounded();
+        for n in 0 .. threads.max(1) {
+            let (job_rx, done_tx, notifier) = (job_rx.clone(), done_tx.clone(), notifier.clone());
+            thread::Builder::new()
+                .name(format!("dirty-{}", n))
+                .spawn(move || {
+                    for (reply, job) in job_rx.iter() {
+                        if done_tx.send((reply, job.run())).is_err() {
+                            break;
+                        }
+                        let _ = notifier.notify();
+                    }
+                })?;
+        }
+        Ok(Pool { jobs, done, notifier: registered })
+    }
+
+    fn submit(&self, reply: Cid, job: Job) {
+        self.jobs.send((reply, job)).expect("dirty schedulers are gone");
+    }
+
+    /// the jobs finished since the last call
+    fn finished(&self) -> Vec<(Cid, Done)> {
+        self.done.try_iter().collect()
+    }
+}
+
+/// serves `ReadFile`, `WriteFile`, `Stat` and `ReadDir` on `threads` dirty schedulers.
+/// Each request is answered to its `reply`, in the order they finish.
+/// If the threads can't be started, the server logs the error and ends.
+pub fn server(id: Cid, threads: usize) -> GenBox {
+    let pool = Pool::new(id, threads);
+
+    Box::pin(Box::new(move |_: ResumeArg| {
+        let pool = match pool {
+            Ok(pool) => pool,
+            Err(e) => {
+                error!("fs: starting the dirty schedulers -> {}", e);
+                done!();
+            }
+        };
+        loop {
+            recv!{
+                WakeUp, w => if w.token() == pool.notifier.token() {
+                    for (reply, done) in pool.finished() {
+                        match done {
+                            Done::Read(r) => send!(reply, r),
+                            Done::Write(r) => send!(reply, r),
+                            Done::Stat(r) => send!(reply, r),
+                            Done::ReadDir(r) => send!(reply, r),
+                        }
+                    }
+                },
+                ReadFile, ReadFile { path, reply } => pool.submit(reply, Job::Read(path)),
+                WriteFile, WriteFile { path, data, reply } => pool.submit(reply, Job::Write(path, data)),
+                Stat, Stat { path, reply } => pool.submit(reply, Job::Stat(path)),
+                ReadDir, ReadDir { path, reply } => pool.submit(reply, Job::ReadDir(path))
+            }
+        }
+    }))
+}
@@ -1,8 +1,6 @@
-use ffi;
 use std::time::Duration;
-use std::{ptr, cmp};
-use syscall_alt::syscalls::Syscall;
-use libc;
+use std::cmp;
+use crate::sys::{aio, Errno, Timespec};
 
 #[derive(Debug)]
 pub enum AIoError {
@@ -23,7 +21,7 @@ pub enum ReadError {
 }
 
 pub struct AIoContext {
-    id:         u64,
+    id:         aio::ContextId,
     capacity:   usize
 }
 impl AIoContext {
@@ -32,17 +30,10 @@ impl AIoContext {
     }
 
     pub fn setup(nr_events: usize) -> Result<AIoContext, AIoError> {
-        let mut id: ffi::aio_context_t = 0;
-        let res = unsafe {
-            Syscall::io_setup.syscall2(
-                nr_events as isize,
-                &mut id as *mut ffi::aio_context_t as isize
-            )
-        };
-        if res == 0 {
-            Ok(AIoContext { id: id, capacity: nr_events })
-        } else {
-            match -res as i32 {
+        let mut id = 0;
+        match unsafe { aio::setup(nr_events as u32, &mut id) } {
+            Ok(()) => Ok(AIoContext { id: id, capacity: nr_events }),
+            Err(e) => match e as i32 {
                 libc::EAGAIN => Err(AIoError::EventLimit),
                 libc::EFAULT => panic!("internal error (invalid pointer)"),
                 libc::EINVAL => Err(AIoError::NotInitialized),
@@ -52,32 +43,20 @@ impl AIoContext {
             }
         }
     }
-    pub fn destroy(self) {
-        let res = unsafe {
-            Syscall::io_destroy.syscall1(self.id as isize)
-        };
-        match -res as i32 {
-            0 => (),
-            libc::EFAULT | libc::EINVAL => panic!("invalid context"),
-            libc::ENOSYS => panic!("attempt to destroy a context, that shouldn't exist"),
-            e => panic!("unknown return code {}", e)
-        }
+    /// waits for the jobs in flight. The context is gone either way, so errors are only worth logging.
+    pub fn destroy(self) -> Result<(), Errno> {
+        unsafe { aio::destroy(self.id) }
     }
     /** The caller has to ensure the buffers contained in the iocbs
         are not dropped until all jobs have finished */
-    pub unsafe fn submit(&self, iocbs: &[*const ffi::Iocb])
+    pub unsafe fn submit(&self, iocbs: &[*const aio::Iocb])
      -> Result<(), AIoError>
     {
         assert!(iocbs.len() <= self.capacity);
-        let res = Syscall::io_submit.syscall3(
-            self.id as isize,
-            iocbs.len() as isize,
-            iocbs.as_ptr() as isize
-        );
-        if res as usize == iocbs.len() {
-            Ok(())
-        } else {
-            match -res as i32 {
+        match aio::submit(self.id, iocbs) {
+            Ok(n) if n == iocbs.len() => Ok(()),
+            Ok(_) => Err(AIoError::KernelRessources),
+            Err(e) => match e as i32 {
                 libc::EAGAIN => Err(AIoError::KernelRessources),
                 libc::EBADF => Err(AIoError::BadFileDescriptor),
                 libc::EFAULT => panic!("internal error (invalid data)"),
@@ -87,42 +66,35 @@ impl AIoContext {
             }
         }
     }
-    
-    
-    pub fn get_events(&self, min: usize, buf: &mut Vec<ffi::Event>, timeout: Option<Duration>)
+
+
+    pub fn get_events(&self, min: usize, buf: &mut Vec<aio::Event>, timeout: Option<Duration>)
      -> Result<(), AIoError> {
         let max = cmp::min(self.capacity, buf.capacity());
         assert!(min <= max);
-        
-        let timeout = timeout.as_ref().map(|t| libc::timespec {
-            tv_sec:  t.as_secs() as libc::time_t,
-            tv_nsec: t.subsec_nanos() as libc::c_long
-        });
-        let timeout_p = match timeout {
-            Some(ref t) => t as *const libc::timespec,
-            None => ptr::null()
-        };
-        
-        let res = unsafe {
-            Syscall::io_getevents.syscall5(
-                self.id as isize,
-                min as isize,
-                max as isize,
-                buf.as_mut_ptr() as isize,
-                timeout_p as isize
-            )
+
+        let timeout = timeout.map(Timespec::from);
+        buf.clear();
+        buf.resize(max, aio::Event::default());
+
+        let res: Result<usize, Errno> = unsafe {
+            aio::getevents(self.id, min, &mut buf[..], timeout.as_ref())
         };
-        
-        if res >= 0 {
-            unsafe { buf.set_len(res as usize) };
-            Ok(())
-        } else {
-            match -res as i32 {
-                libc::EINTR => Err(AIoError::Interrupted),
-                libc::EFAULT => panic!("internal error: invalid events or timeout"),
-                libc::EINVAL => panic!("internal error: ctx invalid or out of range"),
-                libc::ENOSYS => Err(AIoError::NotImplemented),
-                e => panic!("unknown return code {}", e)
+
+        match res {
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(())
+            }
+            Err(e) => {
+                buf.clear();
+                match e as i32 {
+                    libc::EINTR => Err(AIoError::Interrupted),
+                    libc::EFAULT => panic!("internal error: invalid events or timeout"),
+                    libc::EINVAL => panic!("internal error: ctx invalid or out of range"),
+                    libc::ENOSYS => Err(AIoError::NotImplemented),
+                    e => panic!("unknown return code {}", e)
+                }
             }
         }
     }
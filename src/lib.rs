@@ -8,12 +8,31 @@ pub mod macros;
 pub mod message;
 pub mod dispatch;
 pub mod epoll;
+pub mod reactor;
 pub mod net;
 pub mod sys;
+pub mod trace;
+pub mod timeline;
+pub mod metrics;
+pub mod timer;
+pub mod sim;
+pub mod testing;
+pub mod uring;
+pub mod signals;
+pub mod port;
+pub mod watch;
+pub mod eventfd;
+pub mod io;
+pub mod fs;
+pub mod aio;
+pub mod disk_log;
+pub mod persistent;
+pub mod table;
 
 
 pub mod prelude {
     pub use crate::message::*;
     pub use crate::dispatch::*;
     pub use crate::net::*;
+    pub use crate::trace::*;
 }
@@ -18,6 +18,22 @@ macro_rules! send {
     ($msg:expr => $addr:expr) => (no_msg!(yield $crate::dispatch::ProcessYield::Send($addr, $crate::message::Envelope::pack($msg))));
 }
 
+/// send_after!(delay, cid, message)
+///
+/// Send a message to the coroutine identified by cid once `delay` (a `Duration`) has passed.
+#[macro_export]
+macro_rules! send_after {
+    ($delay:expr, $addr:expr, $msg:expr) => (no_msg!(yield $crate::dispatch::ProcessYield::SendAfter($delay, $addr, $crate::message::Envelope::pack($msg))));
+}
+
+/// monitor!(cid)
+///
+/// Receive `Down(cid)` when the coroutine identified by cid terminates, or right away if it already has.
+#[macro_export]
+macro_rules! monitor {
+    ($target:expr) => (no_msg!(yield $crate::dispatch::ProcessYield::Monitor($target)));
+}
+
 #[macro_export]
 macro_rules! io {
     () => (no_msg!(yield $crate::dispatch::ProcessYield::Io))
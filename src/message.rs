@@ -1,4 +1,4 @@
-use std::any::TypeId;
+use std::any::{self, TypeId};
 use std::{mem, ptr};
 use serde::{ser::Serialize, de::DeserializeOwned};
 use std::fmt::{self, Debug};
@@ -47,7 +47,11 @@ enum Data {
 
 pub struct Envelope {
     event: Data,
-    pub type_id: TypeId
+    pub type_id: TypeId,
+    pub type_name: &'static str,
+
+    /// flow id assigned when the dispatcher records a timeline
+    pub(crate) flow: u64
 }
 
 const fn is_inline<T>() -> bool {
@@ -76,11 +80,13 @@ impl Envelope {
 
         Envelope {
             event,
-            type_id: TypeId::of::<T>()
+            type_id: TypeId::of::<T>(),
+            type_name: any::type_name::<T>(),
+            flow: 0
         }
     }
     pub fn unpack<T: Message + 'static>(self) -> T {
-        let Envelope { event, type_id } = self;
+        let Envelope { event, type_id, .. } = self;
         assert_eq!(type_id, TypeId::of::<T>());
         
         if is_inline::<T>() {
@@ -0,0 +1,315 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
+use std::rc::Rc;
+use crate::prelude::*;
+use crate::reactor::{self, WakeUp};
+use crate::sys::epoll::Flags;
+
+/// monotonically increasing value
+#[derive(Clone, Debug, Default)]
+pub struct Counter(Rc<Cell<u64>>);
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, n: u64) {
+        self.0.set(self.0.get() + n);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+/// value that can go up and down
+#[derive(Clone, Debug, Default)]
+pub struct Gauge(Rc<Cell<f64>>);
+impl Gauge {
+    pub fn set(&self, v: f64) {
+        self.0.set(v);
+    }
+    pub fn add(&self, v: f64) {
+        self.0.set(self.0.get() + v);
+    }
+    pub fn get(&self) -> f64 {
+        self.0.get()
+    }
+}
+
+#[derive(Debug)]
+struct HistogramData {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// distribution of observed values over fixed buckets
+#[derive(Clone, Debug)]
+pub struct Histogram(Rc<RefCell<HistogramData>>);
+impl Histogram {
+    /// `bounds` are the upper bounds of the buckets, in increasing order
+    pub fn new(bounds: &[f64]) -> Histogram {
+        Histogram(Rc::new(RefCell::new(HistogramData {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len()],
+            sum: 0.,
+            count: 0,
+        })))
+    }
+    pub fn observe(&self, v: f64) {
+        let mut h = self.0.borrow_mut();
+        if let Some(i) = h.bounds.iter().position(|&b| v <= b) {
+            h.counts[i] += 1;
+        }
+        h.sum += v;
+        h.count += 1;
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+/// current value of a metric
+#[derive(Clone, Debug)]
+pub enum Value {
+    Counter(u64),
+    Gauge(f64),
+
+    /// cumulative counts per upper bound
+    Histogram { buckets: Vec<(f64, u64)>, sum: f64, count: u64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub name: String,
+    pub help: String,
+    pub value: Value,
+}
+
+struct Entry {
+    name: String,
+    help: String,
+    metric: Metric,
+}
+
+/// a set of named metrics.
+/// Cloning the registry gives another handle to the same set, so it can be moved into processes.
+#[derive(Clone, Default)]
+pub struct Registry {
+    entries: Rc<RefCell<Vec<Entry>>>,
+}
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    fn get_or_insert(&self, name: &str, help: &str, create: impl FnOnce() -> Metric) -> Metric {
+        let mut entries = self.entries.borrow_mut();
+        if let Some(e) = entries.iter().find(|e| e.name == name) {
+            return e.metric.clone();
+        }
+        let metric = create();
+        entries.push(Entry { name: name.into(), help: help.into(), metric: metric.clone() });
+        metric
+    }
+
+    /// get the counter `name`, registering it if needed
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        match self.get_or_insert(name, help, || Metric::Counter(Counter::default())) {
+            Metric::Counter(c) => c,
+            _ => panic!("metric {} is not a counter", name)
+        }
+    }
+
+    /// get the gauge `name`, registering it if needed
+    pub fn gauge(&self, name: &str, help: &str) -> Gauge {
+        match self.get_or_insert(name, help, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(g) => g,
+            _ => panic!("metric {} is not a gauge", name)
+        }
+    }
+
+    /// get the histogram `name`, registering it with the given bucket bounds if needed
+    pub fn histogram(&self, name: &str, help: &str, bounds: &[f64]) -> Histogram {
+        match self.get_or_insert(name, help, || Metric::Histogram(Histogram::new(bounds))) {
+            Metric::Histogram(h) => h,
+            _ => panic!("metric {} is not a histogram", name)
+        }
+    }
+
+    /// read all metrics
+    pub fn samples(&self) -> Vec<Sample> {
+        self.entries.borrow().iter().map(|e| {
+            let value = match e.metric {
+                Metric::Counter(ref c) => Value::Counter(c.get()),
+                Metric::Gauge(ref g) => Value::Gauge(g.get()),
+                Metric::Histogram(ref h) => {
+                    let h = h.0.borrow();
+                    let mut total = 0;
+                    let buckets = h.bounds.iter().zip(h.counts.iter()).map(|(&b, &n)| {
+                        total += n;
+                        (b, total)
+                    }).collect();
+                    Value::Histogram { buckets, sum: h.sum, count: h.count }
+                }
+            };
+            Sample { name: e.name.clone(), help: e.help.clone(), value }
+        }).collect()
+    }
+
+    /// render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for sample in self.samples() {
+            let kind = match sample.value {
+                Value::Counter(_) => "counter",
+                Value::Gauge(_) => "gauge",
+                Value::Histogram { .. } => "histogram",
+            };
+            let name = &sample.name;
+            writeln!(out, "# HELP {} {}", name, escape_help(&sample.help)).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            match sample.value {
+                Value::Counter(n) => writeln!(out, "{} {}", name, n).unwrap(),
+                Value::Gauge(v) => writeln!(out, "{} {}", name, v).unwrap(),
+                Value::Histogram { ref buckets, sum, count } => {
+                    for &(bound, n) in buckets {
+                        writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, n).unwrap();
+                    }
+                    writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+                    writeln!(out, "{}_sum {}", name, sum).unwrap();
+                    writeln!(out, "{}_count {}", name, count).unwrap();
+                }
+            }
+        }
+        out
+    }
+}
+
+/// HELP lines end at a newline, so it and the escape character are escaped
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// metrics the dispatcher keeps about itself
+pub(crate) struct DispatchMetrics {
+    pub queue_depth: Gauge,
+    pub messages: Counter,
+    pub processes: Gauge,
+    pub spawns: Counter,
+    pub exits: Counter,
+    pub futures: Gauge,
+    pub idle_spins: Counter,
+    pub idle_yields: Counter,
+    pub idle_blocks: Counter,
+    pub epoll_wakeups: Counter,
+    pub events_per_wakeup: Histogram,
+}
+impl DispatchMetrics {
+    pub fn new(r: &Registry) -> DispatchMetrics {
+        DispatchMetrics {
+            queue_depth: r.gauge("emp_queue_depth", "entries in the run queue"),
+            messages: r.counter("emp_messages_total", "messages sent"),
+            processes: r.gauge("emp_processes", "live processes"),
+            spawns: r.counter("emp_spawns_total", "processes spawned"),
+            exits: r.counter("emp_exits_total", "processes terminated"),
+            futures: r.gauge("emp_futures_pending", "futures not yet completed"),
+            idle_spins: r.counter("emp_idle_spins_total", "non-blocking polls while idle"),
+            idle_yields: r.counter("emp_idle_yields_total", "thread yields while idle"),
+            idle_blocks: r.counter("emp_idle_blocks_total", "blocking waits for IO or timers"),
+            epoll_wakeups: r.counter("emp_epoll_wakeups_total", "returns from the reactor wait"),
+            events_per_wakeup: r.histogram("emp_epoll_events_per_wakeup", "wake ups returned by a single reactor wait", EVENTS_PER_WAKEUP),
+        }
+    }
+}
+
+/// upper bounds for `emp_epoll_events_per_wakeup`
+const EVENTS_PER_WAKEUP: &[f64] = &[1., 2., 4., 8., 16., 32., 64., 128., 256., 512., 1024.];
+
+/// serves `registry` over HTTP.
+/// Expects `Connection`s, so it is meant to be the reciever of a `listener`:
+///
+/// ```ignore
+/// let exporter = d.spawn(metrics::exporter(d.metrics()));
+/// d.spawn2(Box::new(move |cid| listener(cid, [127, 0, 0, 1].into(), 9100, exporter)));
+/// ```
+pub fn exporter(registry: Registry) -> GenBox {
+    dispatcher!{
+        Connection, c => {
+            let registry = registry.clone();
+            spawn!(|cid| http_responder(cid, c, registry));
+        }
+    }
+}
+
+/// answers a single request on `conn` with the rendered metrics, then closes it.
+/// requests are only a few lines, anything longer than this is not a scrape
+const MAX_REQUEST_SIZE: usize = 8192;
+
+fn http_responder(id: Cid, conn: Connection, registry: Registry) -> GenBox {
+    let conn = reactor::register(conn, id, Flags::In);
+
+    Box::pin(Box::new(move |_: ResumeArg| {
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("metrics: dropping connection -> {}", e);
+                done!();
+            }
+        };
+        let mut buf = Vec::with_capacity(1024);
+        let mut response = Vec::new();
+        let mut written = 0;
+        loop {
+            recv!{
+                WakeUp, _ => {
+                    if response.len() == 0 {
+                        match conn.recv_into(&mut buf) {
+                            Ok(None) => {},
+                            Ok(Some(0)) => done!(),
+                            Err(e) => {
+                                debug!("metrics: dropping connection -> {}", e);
+                                done!();
+                            }
+                            Ok(Some(_)) if buf.len() > MAX_REQUEST_SIZE => {
+                                debug!("metrics: dropping connection -> request header too large");
+                                done!();
+                            }
+                            Ok(Some(_)) => if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                                let body = registry.render();
+                                response = format!(
+                                    "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                                    body.len(), body
+                                ).into_bytes();
+                                if let Err(e) = conn.modify(Flags::Out) {
+                                    debug!("metrics: dropping connection -> {}", e);
+                                    done!();
+                                }
+                            }
+                        }
+                    } else {
+                        loop {
+                            match conn.send(&response[written ..]) {
+                                Ok(Some(n)) => {
+                                    written += n;
+                                    if written == response.len() {
+                                        done!();
+                                    }
+                                }
+                                Ok(None) => break,
+                                Err(e) => {
+                                    debug!("metrics: dropping connection -> {}", e);
+                                    done!();
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }))
+}
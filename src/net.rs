@@ -1,7 +1,8 @@
-use crate::epoll::{self, WakeUp};
+use crate::reactor::{self, WakeUp, Registered};
 use crate::sys::{
     self,
-    epoll::{Flags, Event},
+    Errno,
+    epoll::Flags,
     sock
 };
 use slotmap::KeyData;
@@ -13,6 +14,7 @@ use crate::prelude::*;
 use crate::message::Envelope;
 const MIN_RECV_SIZE: usize = 128;
 const EWOULDBLOCK: i64 = libc::EWOULDBLOCK as i64;
+const ECONNRESET: i64 = libc::ECONNRESET as i64;
 
 #[derive(Debug)]
 pub struct Line(pub String);
@@ -64,7 +66,7 @@ pub struct Connection {
     remote: (Ipv4Addr, u16)
 }
 impl Connection {
-    pub fn recv_into(&self, buf: &mut Vec<u8>) -> Option<usize> {
+    pub fn recv_into(&self, buf: &mut Vec<u8>) -> Result<Option<usize>, Errno> {
         let start = buf.len();
         let mut size = buf.capacity() - start;
         // make sure we have some space to read into
@@ -78,19 +80,73 @@ impl Connection {
             sys::msg::recv(self.fd, gap, sys::msg::Flags::DontWait)
         };
         match r {
-            Ok(0) => Some(0),
+            Ok(0) => Ok(Some(0)),
             Ok(n) => {
                 unsafe {
                     buf.set_len(start + n);
                 }
-                Some(n)
+                Ok(Some(n))
             },
-            Err(EWOULDBLOCK) => None,
-            Err(e) => panic!("got error: {}", e)
+            Err(EWOULDBLOCK) => Ok(None),
+            // the peer is gone either way
+            Err(ECONNRESET) => Ok(Some(0)),
+            Err(e) => Err(e)
+        }
+    }
+    /// write as much of `data` as the socket accepts without blocking.
+    /// `None` if it accepts nothing, an error if the peer reset the connection or went away.
+    pub fn send(&self, data: &[u8]) -> Result<Option<usize>, Errno> {
+        let r = unsafe {
+            sys::msg::send(self.fd, data, sys::msg::Flags::DontWait | sys::msg::Flags::NoSignal)
+        };
+        match r {
+            Ok(n) => Ok(Some(n)),
+            Err(EWOULDBLOCK) => Ok(None),
+            Err(e) => Err(e)
         }
     }
     pub fn remote(&self) -> (Ipv4Addr, u16) { self.remote }
 }
+/// a byte stream
+pub trait Stream: 'static {
+    /// read whatever is available. `None` if nothing is, `Some(0)` if the stream is closed.
+    fn recv_into(&self, buf: &mut Vec<u8>) -> Result<Option<usize>, Errno>;
+
+    /// write as much of `data` as possible without blocking. `None` if nothing could be written.
+    fn send(&self, data: &[u8]) -> Result<Option<usize>, Errno>;
+}
+
+/// a stream that can wake up a process when data arrives
+pub trait Watch {
+    type Watched: Stream;
+
+    /// deliver a `WakeUp` to `id` whenever the stream becomes readable
+    fn watch(self, id: Cid) -> Result<Self::Watched, Errno>;
+}
+
+impl Stream for Connection {
+    fn recv_into(&self, buf: &mut Vec<u8>) -> Result<Option<usize>, Errno> {
+        Connection::recv_into(self, buf)
+    }
+    fn send(&self, data: &[u8]) -> Result<Option<usize>, Errno> {
+        Connection::send(self, data)
+    }
+}
+impl<S: Stream + AsRawFd> Stream for Registered<S> {
+    fn recv_into(&self, buf: &mut Vec<u8>) -> Result<Option<usize>, Errno> {
+        (**self).recv_into(buf)
+    }
+    fn send(&self, data: &[u8]) -> Result<Option<usize>, Errno> {
+        (**self).send(data)
+    }
+}
+impl Watch for Connection {
+    type Watched = Registered<Connection>;
+    fn watch(self, id: Cid) -> Result<Registered<Connection>, Errno> {
+        reactor::register(self, id, Flags::In)
+    }
+}
+
 impl AsRawFd for Connection {
     fn as_raw_fd(&self) -> RawFd {
         self.fd
@@ -102,11 +158,17 @@ impl Drop for Connection {
     }
 }
     
-pub fn line_reader(id: Cid, conn: Connection, reciever: Cid) -> GenBox {
-    let event = Event { events: Flags::In, data: id.as_ffi() };
-    let registration = epoll::register(conn, event);
+pub fn line_reader<C: Watch>(id: Cid, conn: C, reciever: Cid) -> GenBox {
+    let registration = conn.watch(id);
 
     Box::pin(Box::new(move |_: ResumeArg| {
+        let registration = match registration {
+            Ok(r) => r,
+            Err(e) => {
+                error!("line_reader: register -> {}", e);
+                done!();
+            }
+        };
         let mut cursor = 0; // end of pending data
         let mut buf = Vec::with_capacity(2*MIN_RECV_SIZE);
         
@@ -114,14 +176,19 @@ pub fn line_reader(id: Cid, conn: Connection, reciever: Cid) -> GenBox {
             recv!{
                 WakeUp, _ => {
                     match registration.recv_into(&mut buf) {
-                        None => {
+                        Ok(None) => {
                             io!();
                         },
-                        Some(0) => {
+                        Ok(Some(0)) => {
+                            send!(reciever, Closed);
+                            return ProcessExit::Done;
+                        },
+                        Err(e) => {
+                            error!("line_reader: recv -> {}", e);
                             send!(reciever, Closed);
                             return ProcessExit::Done;
                         },
-                        Some(n) => {
+                        Ok(Some(n)) => {
                             if let Some(end) = buf[cursor .. cursor + n].iter().position(|&b| b == b'\n') {
                                 let remaining = buf.split_off(end+1);
                                 let line = mem::replace(&mut buf, remaining);
@@ -142,10 +209,16 @@ pub fn line_reader(id: Cid, conn: Connection, reciever: Cid) -> GenBox {
 
 pub fn listener(id: Cid, addr: IpAddr, port: u16, reciever: Cid) -> GenBox {
     let socket = Socket::listen(addr, port, 10);
-    let event = Event { events: Flags::In, data: id.as_ffi() };
-    let socket = epoll::register(socket, event);
+    let socket = reactor::register(socket, id, Flags::In);
     Box::pin(Box::new({
         move |_: ResumeArg| {
+            let socket = match socket {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("listener: register -> {}", e);
+                    done!();
+                }
+            };
             loop {
                 recv!{
                     WakeUp, _ => {
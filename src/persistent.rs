@@ -0,0 +1,419 @@
+//! Event sourced processes, which keep their state across restarts
+//!
+//! ```ignore
+//! impl PersistentActor for Account {
+//!     type Command = Withdraw;
+//!     type Event = Withdrawn;
+//!
+//!     fn handle(&self, cmd: Withdraw, ctx: &mut Context<Withdrawn>) {
+//!         let mut balance = self.balance;
+//!         if cmd.amount <= balance {
+//!             ctx.persist(Withdrawn(cmd.amount));
+//!             // not applied yet, so the reply says what it will be
+//!             balance -= cmd.amount;
+//!         }
+//!         ctx.reply(cmd.reply, Balance(balance));
+//!     }
+//!     fn apply(&mut self, event: &Withdrawn) {
+//!         self.balance -= event.0;
+//!     }
+//! }
+//!
+//! let account = Persistent::open("/var/lib/accounts/42", Account::default())?;
+//! let account = spawn!(|id| persistent::actor(id, account));
+//! ```
+//!
+//! A command is turned into events, which are written to a `DiskLog` in the directory as one record,
+//! so after a crash either all of them are replayed or none.
+//! Only once they are on disk are they applied and the replies sent.
+//! Opening the directory again replays the journal, after the latest snapshot if there is one.
+
+use std::any::TypeId;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::mem;
+use serde::{ser::Serialize, de::DeserializeOwned};
+use crate::prelude::*;
+use crate::reactor::WakeUp;
+use crate::disk_log::{self, crc32, DiskLog, Worker, Job, Done};
+
+const SNAPSHOT: &str = "snapshot";
+const JOURNAL: &str = "journal";
+const REPLAY_BATCH: usize = 1024;
+
+/// a process whose state is rebuilt from the events it persisted
+pub trait PersistentActor: Sized + 'static {
+    /// the messages it handles, others are ignored
+    type Command: Message + 'static;
+
+    /// what is written to the journal, with bincode like the records of a `DiskLog`
+    type Event: Serialize + DeserializeOwned + Message + 'static;
+
+    /// decide what `command` changes. The state is not changed yet,
+    /// that happens in `apply` once the events are persisted.
+    fn handle(&self, command: Self::Command, ctx: &mut Context<Self::Event>);
+
+    /// change the state. Also called while replaying the journal,
+    /// so it may not fail or have other effects.
+    fn apply(&mut self, event: &Self::Event);
+}
+
+/// what a command results in
+pub struct Context<E> {
+    events: Vec<E>,
+    replies: Vec<(Cid, Envelope)>,
+}
+impl<E> Context<E> {
+    pub fn persist(&mut self, event: E) {
+        self.events.push(event);
+    }
+
+    /// send `msg` to `to` once the events are persisted
+    pub fn reply<M: Message + 'static>(&mut self, to: Cid, msg: M) {
+        self.replies.push((to, Envelope::pack(msg)));
+    }
+}
+
+struct Snapshots<A> {
+    /// events between two snapshots
+    every: u64,
+    save: fn(&A) -> bincode::Result<Vec<u8>>,
+}
+
+/// the recovered state of an actor and its journal, to be run with `actor`
+pub struct Persistent<A> {
+    state: A,
+    dir: PathBuf,
+    log: DiskLog,
+    snapshots: Option<Snapshots<A>>,
+
+    /// events applied since the last snapshot
+    since_snapshot: u64,
+}
+impl<A: PersistentActor> Persistent<A> {
+    /// replay the journal in `dir` onto `init`, creating the directory if needed
+    pub fn open(dir: impl AsRef<Path>, init: A) -> io::Result<Persistent<A>> {
+        Persistent::recover(dir.as_ref(), init, 0, None)
+    }
+
+    fn recover(dir: &Path, init: A, from: u64, snapshots: Option<Snapshots<A>>) -> io::Result<Persistent<A>> {
+        let log = DiskLog::open(dir.join(JOURNAL), disk_log::Options::default())?;
+        let mut state = init;
+        let mut next = from;
+        let mut replayed = 0;
+        loop {
+            let commands = log.read::<Vec<A::Event>>(next, REPLAY_BATCH)?;
+            if commands.len() == 0 {
+                break;
+            }
+            for (offset, events) in commands {
+                for event in &events {
+                    state.apply(event);
+                }
+                next = offset + 1;
+                replayed += events.len() as u64;
+            }
+        }
+        debug!("replayed {} events from {:?}", replayed, dir);
+        Ok(Persistent { state, dir: dir.to_owned(), log, snapshots, since_snapshot: replayed })
+    }
+
+    pub fn state(&self) -> &A {
+        &self.state
+    }
+
+    /// write `events` to the journal as one record, to be committed by the worker.
+    /// Returns false if that failed.
+    fn append(&mut self, events: &[A::Event]) -> bool {
+        match self.log.append(events) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("persistent: append to {:?} -> {}", self.dir, e);
+                false
+            }
+        }
+    }
+
+    fn apply(&mut self, events: Vec<A::Event>) {
+        for event in events {
+            self.state.apply(&event);
+            self.since_snapshot += 1;
+        }
+        let due = match self.snapshots {
+            Some(ref s) => self.since_snapshot >= s.every,
+            None => false
+        };
+        if due {
+            match self.save_snapshot() {
+                Ok(()) => self.since_snapshot = 0,
+                Err(e) => warn!("persistent: snapshot of {:?} -> {}", self.dir, e)
+            }
+        }
+    }
+
+    /// the state after all commands before `next_offset`. This blocks while it is written.
+    fn save_snapshot(&mut self) -> io::Result<()> {
+        let save = self.snapshots.as_ref().unwrap().save;
+        let data = save(&self.state).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&self.log.next_offset().to_le_bytes())?;
+        file.write_all(&crc32(&data).to_le_bytes())?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT))?;
+        fs::File::open(&self.dir)?.sync_all()
+    }
+}
+impl<A: PersistentActor + Serialize + DeserializeOwned> Persistent<A> {
+    /// like `open`, but start from the latest snapshot, and take one every `every` events
+    pub fn open_with_snapshots(dir: impl AsRef<Path>, init: A, every: u64) -> io::Result<Persistent<A>> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let snapshots = Snapshots { every: every.max(1), save: |a: &A| bincode::serialize(a) };
+        match load_snapshot::<A>(&dir.join(SNAPSHOT))? {
+            Some((next, state)) => Persistent::recover(dir, state, next, Some(snapshots)),
+            None => Persistent::recover(dir, init, 0, Some(snapshots))
+        }
+    }
+}
+
+/// the offset after the snapshot and the state. None if there is none, or it is damaged.
+/// Like the journal, the state is stored without a type id, so other builds can read it.
+fn load_snapshot<A: DeserializeOwned>(path: &Path) -> io::Result<Option<(u64, A)>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    if data.len() < 12 {
+        warn!("ignoring truncated snapshot {:?}", path);
+        return Ok(None);
+    }
+    let mut next = [0; 8];
+    next.copy_from_slice(&data[.. 8]);
+    let crc = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+    if crc32(&data[12 ..]) != crc {
+        warn!("ignoring damaged snapshot {:?}", path);
+        return Ok(None);
+    }
+    match bincode::deserialize(&data[12 ..]) {
+        Ok(state) => Ok(Some((u64::from_le_bytes(next), state))),
+        // written by a build whose state serializes differently
+        Err(e) => {
+            warn!("ignoring snapshot {:?} -> {}", path, e);
+            Ok(None)
+        }
+    }
+}
+
+/// runs `persistent`, handling its `Command`s one at a time.
+/// Messages that arrive while events are being persisted are handled afterwards.
+/// If the journal cannot be written, the process terminates without applying the events.
+pub fn actor<A: PersistentActor>(id: Cid, persistent: Persistent<A>) -> GenBox {
+    let mut persistent = persistent;
+    let worker = Worker::new(id);
+
+    Box::pin(Box::new(move |_: ResumeArg| {
+        let worker = match worker {
+            Ok(worker) => worker,
+            Err(e) => {
+                error!("persistent: starting the worker -> {}", e);
+                done!();
+            }
+        };
+        let mut stash: VecDeque<Envelope> = VecDeque::new();
+        loop {
+            let envelope = match stash.pop_front() {
+                Some(envelope) => envelope,
+                None => match (yield ProcessYield::Empty) {
+                    ResumeArg::Message(envelope) => envelope,
+                    ResumeArg::Empty => continue,
+                    _ => unreachable!()
+                }
+            };
+            if envelope.type_id != TypeId::of::<A::Command>() {
+                continue;
+            }
+            let mut ctx = Context { events: vec![], replies: vec![] };
+            persistent.state.handle(envelope.unpack(), &mut ctx);
+
+            if ctx.events.len() > 0 {
+                if !persistent.append(&ctx.events) {
+                    done!();
+                }
+                match persistent.log.take_unsynced() {
+                    Ok(files) => worker.submit(Job::Sync(files)),
+                    Err(e) => {
+                        error!("persistent: {}", e);
+                        done!();
+                    }
+                }
+                // wait for the commit, and keep everything else for later
+                let synced = loop {
+                    match (yield ProcessYield::Empty) {
+                        ResumeArg::Message(envelope) => {
+                            if envelope.type_id != TypeId::of::<WakeUp>() {
+                                stash.push_back(envelope);
+                                continue;
+                            }
+                            let synced = worker.finished().into_iter().find_map(|done| match done {
+                                Done::Synced(result) => Some(result),
+                                _ => None
+                            });
+                            if let Some(result) = synced {
+                                break result;
+                            }
+                        }
+                        ResumeArg::Empty => {}
+                        _ => unreachable!()
+                    }
+                };
+                if let Err(e) = synced {
+                    error!("persistent: sync of {:?} -> {}", persistent.dir, e);
+                    done!();
+                }
+                persistent.apply(mem::take(&mut ctx.events));
+            }
+
+            for (to, msg) in ctx.replies {
+                no_msg!(yield ProcessYield::Send(to, msg));
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Serializer, Deserialize, Deserializer};
+
+    /// the words it was sent, in order
+    struct Words(Vec<String>);
+    impl Serialize for Words {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            self.0.serialize(s)
+        }
+    }
+    impl<'de> Deserialize<'de> for Words {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Words, D::Error> {
+            Vec::deserialize(d).map(Words)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Add {
+        words: Vec<String>,
+        reply: Cid,
+    }
+
+    impl PersistentActor for Words {
+        type Command = Add;
+        type Event = String;
+
+        fn handle(&self, cmd: Add, ctx: &mut Context<String>) {
+            for word in cmd.words {
+                ctx.persist(word);
+            }
+            ctx.reply(cmd.reply, self.0.len());
+        }
+        fn apply(&mut self, word: &String) {
+            self.0.push(word.clone());
+        }
+    }
+
+    #[test]
+    fn the_events_of_a_command_are_one_record() {
+        let dir = std::env::temp_dir().join(format!("emp-persistent-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let words = Persistent::open(&dir, Words(vec![])).unwrap();
+
+        let mut d = Dispatcher::new();
+        let actor = d.spawn2(Box::new(move |id| actor(id, words)));
+        let owner = d.spawn(dispatcher!{ usize, _ => exit!("replied") });
+        d.send(actor, Envelope::pack(Add { words: vec!["a".into(), "b".into()], reply: owner }));
+        d.run();
+        drop(d);
+
+        let words = Persistent::open(&dir, Words(vec![])).unwrap();
+        assert_eq!(words.state().0, ["a", "b"]);
+        assert_eq!(words.log.next_offset(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// an empty directory for one test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("emp-persistent-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// send each of `commands` to an actor running `words`, until all are answered
+    fn run(words: Persistent<Words>, commands: &[&[&str]]) {
+        let mut d = Dispatcher::new();
+        let actor = d.spawn2(Box::new(move |id| actor(id, words)));
+        let mut left = commands.len();
+        let owner = d.spawn(dispatcher!{ usize, _ => {
+            left -= 1;
+            if left == 0 {
+                exit!("replied");
+            }
+        }});
+        for words in commands {
+            let words = words.iter().map(|&w| w.to_string()).collect();
+            d.send(actor, Envelope::pack(Add { words, reply: owner }));
+        }
+        d.run();
+    }
+
+    /// a snapshot after the first command, and one more event after it
+    fn snapshotted(name: &str) -> PathBuf {
+        let dir = scratch(name);
+        let words = Persistent::open_with_snapshots(&dir, Words(vec![]), 2).unwrap();
+        run(words, &[&["a", "b"], &["c"]]);
+        dir
+    }
+
+    #[test]
+    fn replay_starts_after_the_snapshot() {
+        let dir = snapshotted("snapshot");
+        let words = Persistent::open_with_snapshots(&dir, Words(vec![]), 2).unwrap();
+        assert_eq!(words.state().0, ["a", "b", "c"]);
+        // only the command after the snapshot was replayed
+        assert_eq!(words.since_snapshot, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_damaged_snapshot_falls_back_to_a_full_replay() {
+        let dir = snapshotted("damaged");
+        let path = dir.join(SNAPSHOT);
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, data).unwrap();
+
+        let words = Persistent::open_with_snapshots(&dir, Words(vec![]), 2).unwrap();
+        assert_eq!(words.state().0, ["a", "b", "c"]);
+        assert_eq!(words.since_snapshot, 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_snapshot_of_another_type_falls_back_to_a_full_replay() {
+        let dir = snapshotted("other-type");
+        // a valid checksum, but too short for a Vec
+        let state = [0xff];
+        let mut data = 1u64.to_le_bytes().to_vec();
+        data.extend_from_slice(&crc32(&state).to_le_bytes());
+        data.extend_from_slice(&state);
+        fs::write(dir.join(SNAPSHOT), data).unwrap();
+
+        let words = Persistent::open_with_snapshots(&dir, Words(vec![]), 2).unwrap();
+        assert_eq!(words.state().0, ["a", "b", "c"]);
+        assert_eq!(words.since_snapshot, 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
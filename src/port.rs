@@ -0,0 +1,426 @@
+//! External programs as processes, modeled on Erlang ports
+//!
+//! ```ignore
+//! let mut command = Command::new("grep");
+//! command.arg("--line-buffered").arg("error");
+//! let port = port::open(port::spawn(command)?)?;
+//! let port = spawn!(|id| port::server(id, port, Framing::Line, me));
+//! send!(port, Input(b"no\nan error\n".to_vec()));
+//! send!(port, CloseInput);
+//! // me receives Output { stream: Stdout, data: b"an error" } and then Exit { code: Some(0) }
+//! ```
+//!
+//! The port owns the child's stdio pipes and a pidfd, all registered with the reactor,
+//! so nothing blocks the dispatcher.
+
+use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, Stdio};
+use std::{io, mem};
+use crate::prelude::*;
+use crate::reactor::{self, WakeUp, Registered, Token};
+use crate::sys::{self, Errno, epoll::Flags};
+
+const EAGAIN: Errno = libc::EAGAIN as Errno;
+const READ_SIZE: usize = 4096;
+
+/// how output is split into messages and input is framed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// whatever a read returns
+    Raw,
+
+    /// one message per line, without the newline
+    Line,
+
+    /// each frame is preceded by its length as a 4 byte big endian integer
+    LengthPrefixed,
+}
+impl Framing {
+    /// take the complete frames off the front of `buf`. At the end of the stream, the rest is one last frame.
+    fn split(self, buf: &mut Vec<u8>, eof: bool) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        match self {
+            Framing::Raw => if buf.len() > 0 {
+                frames.push(mem::take(buf));
+            },
+            Framing::Line => {
+                while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                    let rest = buf.split_off(end + 1);
+                    let mut line = mem::replace(buf, rest);
+                    line.pop();
+                    frames.push(line);
+                }
+                if eof && buf.len() > 0 {
+                    frames.push(mem::take(buf));
+                }
+            }
+            Framing::LengthPrefixed => {
+                while buf.len() >= 4 {
+                    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+                    if buf.len() < 4 + len {
+                        break;
+                    }
+                    frames.push(buf[4 .. 4 + len].to_vec());
+                    buf.drain(.. 4 + len);
+                }
+                if eof && buf.len() > 0 {
+                    debug!("dropping {} bytes of an incomplete frame", buf.len());
+                    buf.clear();
+                }
+            }
+        }
+        frames
+    }
+
+    fn encode(self, data: Vec<u8>) -> Vec<u8> {
+        match self {
+            Framing::LengthPrefixed => {
+                let mut frame = Vec::with_capacity(4 + data.len());
+                frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                frame.extend(data);
+                frame
+            }
+            _ => data
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// a frame of the program's output
+#[derive(Debug)]
+pub struct Output {
+    pub port: Cid,
+    pub stream: Stream,
+    pub data: Vec<u8>,
+}
+
+/// the program terminated, after all of its output was delivered.
+/// `code` is None if it was killed by `signal`.
+#[derive(Debug)]
+pub struct Exit {
+    pub port: Cid,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+/// write a frame to the program's stdin
+#[derive(Debug)]
+pub struct Input(pub Vec<u8>);
+
+/// close the program's stdin once all input is written
+#[derive(Debug)]
+pub struct CloseInput;
+
+/// kill the program
+#[derive(Debug)]
+pub struct Kill;
+
+/// an owned file descriptor
+struct Fd(RawFd);
+impl Fd {
+    fn nonblocking(fd: RawFd) -> io::Result<Fd> {
+        let fd = Fd(fd);
+        unsafe { sys::set_nonblocking(fd.0) }.map_err(errno)?;
+        Ok(fd)
+    }
+}
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = sys::close(self.0);
+        }
+    }
+}
+
+struct Reader {
+    // None at the end of the stream
+    fd: Option<Registered<Fd>>,
+    buf: Vec<u8>,
+}
+impl Reader {
+    fn new(fd: Fd, id: Cid) -> Result<Reader, Errno> {
+        Ok(Reader {
+            fd: Some(reactor::register(fd, id, Flags::In)?),
+            buf: Vec::new(),
+        })
+    }
+    fn token(&self) -> Option<Token> {
+        self.fd.as_ref().map(|fd| fd.token())
+    }
+
+    /// read what is available and return the complete frames
+    fn read(&mut self, framing: Framing) -> Vec<Vec<u8>> {
+        let mut eof = false;
+        if let Some(ref fd) = self.fd {
+            loop {
+                let len = self.buf.len();
+                self.buf.resize(len + READ_SIZE, 0);
+                let r = unsafe { sys::read(fd.as_raw_fd(), &mut self.buf[len ..]) };
+                self.buf.truncate(len + *r.as_ref().unwrap_or(&0));
+                match r {
+                    Ok(0) => { eof = true; break; }
+                    Ok(_) => {}
+                    Err(EAGAIN) => break,
+                    Err(e) => {
+                        debug!("read from port -> {:?}", e);
+                        eof = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if eof {
+            // a closed pipe stays readable
+            self.fd = None;
+        }
+        framing.split(&mut self.buf, eof)
+    }
+}
+
+/// write as much of `pending` as the pipe takes. Re-arms `stdin` if some is left.
+/// Returns false if the program closed its stdin.
+fn write_pending(stdin: &Registered<Fd>, pending: &mut Vec<u8>) -> bool {
+    while pending.len() > 0 {
+        match unsafe { sys::write(stdin.as_raw_fd(), pending) } {
+            Ok(n) => { pending.drain(.. n); }
+            Err(EAGAIN) => {
+                if let Err(e) = stdin.modify(Flags::Out | Flags::OneShot) {
+                    debug!("re-arm port stdin -> {:?}", e);
+                    pending.clear();
+                    return false;
+                }
+                break;
+            }
+            Err(e) => {
+                debug!("write to port -> {:?}", e);
+                pending.clear();
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// start `command` with piped stdio, to be passed to `open`
+pub fn spawn(mut command: Command) -> io::Result<Child> {
+    command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+}
+
+/// a child with piped stdio, ready to be served by `server`
+pub struct Port {
+    child: Child,
+    exited: Fd,
+    stdout: Fd,
+    stderr: Fd,
+    stdin: Fd,
+}
+
+/// take over the stdio pipes of `child` and open a pidfd for it.
+/// If that fails, the child is killed.
+pub fn open(mut child: Child) -> io::Result<Port> {
+    let fds = (|| Ok::<_, io::Error>((
+        Fd(unsafe { sys::pidfd_open(child.id()) }.map_err(errno)?),
+        Fd::nonblocking(child.stdout.take().expect("stdout not piped").into_raw_fd())?,
+        Fd::nonblocking(child.stderr.take().expect("stderr not piped").into_raw_fd())?,
+        Fd::nonblocking(child.stdin.take().expect("stdin not piped").into_raw_fd())?,
+    )))();
+    match fds {
+        Ok((exited, stdout, stderr, stdin)) => Ok(Port { child, exited, stdout, stderr, stdin }),
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
+        }
+    }
+}
+
+fn errno(e: Errno) -> io::Error {
+    io::Error::from_raw_os_error(e as i32)
+}
+
+/// serves `port`. `owner` receives its `Output`, framed with `framing`, and its `Exit`.
+/// The port accepts `Input`, `CloseInput` and `Kill`. `Input` that arrives after stdin was closed is dropped.
+/// The port monitors `owner`, and kills the program and ends when the owner terminates.
+///
+/// `Exit` is sent once the program terminated and its stdout and stderr are closed,
+/// so a program that passed them on to a child of its own only exits when that child is done with them.
+pub fn server(id: Cid, port: Port, framing: Framing, owner: Cid) -> GenBox {
+    let Port { mut child, exited, stdout, stderr, stdin } = port;
+    let registered = (|| Ok::<_, Errno>((
+        reactor::register(exited, id, Flags::In)?,
+        Reader::new(stdout, id)?,
+        Reader::new(stderr, id)?,
+        // nothing to write yet, but errors are reported
+        reactor::register(stdin, id, Flags::OneShot)?,
+    )))();
+
+    Box::pin(Box::new(move |_: ResumeArg| {
+        let (exited, mut stdout, mut stderr, stdin) = match registered {
+            Ok(r) => r,
+            Err(e) => {
+                error!("port: register -> {:?}", e);
+                let _ = child.kill();
+                let _ = child.wait();
+                done!();
+            }
+        };
+        monitor!(owner);
+        // None once the program was reaped, a pidfd stays readable
+        let mut exited = Some(exited);
+        let mut status = None;
+        let mut stdin = Some(stdin);
+        let mut pending = Vec::new();
+        let mut close_input = false;
+        loop {
+            recv!{
+                WakeUp, w => {
+                    if Some(w.token()) == exited.as_ref().map(|fd| fd.token()) {
+                        exited = None;
+                        status = Some(match child.wait() {
+                            Ok(status) => (status.code(), status.signal()),
+                            Err(_) => (None, None)
+                        });
+                        // the pipes may still hold output
+                        for frame in stdout.read(framing) {
+                            send!(owner, Output { port: id, stream: Stream::Stdout, data: frame });
+                        }
+                        for frame in stderr.read(framing) {
+                            send!(owner, Output { port: id, stream: Stream::Stderr, data: frame });
+                        }
+                    } else if Some(w.token()) == stdout.token() {
+                        for frame in stdout.read(framing) {
+                            send!(owner, Output { port: id, stream: Stream::Stdout, data: frame });
+                        }
+                    } else if Some(w.token()) == stderr.token() {
+                        for frame in stderr.read(framing) {
+                            send!(owner, Output { port: id, stream: Stream::Stderr, data: frame });
+                        }
+                    } else if let Some(ref fd) = stdin {
+                        if !write_pending(fd, &mut pending) || (close_input && pending.len() == 0) {
+                            stdin = None;
+                        }
+                    }
+                    if let (Some((code, signal)), None, None) = (status, stdout.token(), stderr.token()) {
+                        send!(owner, Exit { port: id, code, signal });
+                        done!();
+                    }
+                },
+                Input, Input(data) => match stdin {
+                    Some(ref fd) if !close_input => {
+                        pending.extend(framing.encode(data));
+                        if !write_pending(fd, &mut pending) {
+                            stdin = None;
+                        }
+                    }
+                    _ => debug!("port: dropping {} bytes of input, stdin is closed", data.len())
+                },
+                CloseInput, _ => {
+                    close_input = true;
+                    if pending.len() == 0 {
+                        stdin = None;
+                    }
+                },
+                Kill, _ => {
+                    if exited.is_some() {
+                        let _ = child.kill();
+                    }
+                },
+                Down, Down(cid) => if cid == owner {
+                    if exited.is_some() {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                    }
+                    done!();
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn frames(framing: Framing, buf: &mut Vec<u8>, eof: bool) -> Vec<String> {
+        framing.split(buf, eof).into_iter().map(|f| String::from_utf8(f).unwrap()).collect()
+    }
+
+    #[test]
+    fn lines_keep_a_partial_line_until_the_end() {
+        let mut buf = b"one\n\ntwo\nthr".to_vec();
+        assert_eq!(frames(Framing::Line, &mut buf, false), ["one", "", "two"]);
+        assert_eq!(buf, b"thr");
+        buf.extend_from_slice(b"ee");
+        assert_eq!(frames(Framing::Line, &mut buf, true), ["three"]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn length_prefixed_frames_wait_for_their_length() {
+        let mut buf = Framing::LengthPrefixed.encode(b"hello".to_vec());
+        buf.extend(Framing::LengthPrefixed.encode(vec![]));
+        buf.extend(&Framing::LengthPrefixed.encode(b"cut".to_vec())[.. 5]);
+        assert_eq!(frames(Framing::LengthPrefixed, &mut buf, false), ["hello", ""]);
+        assert_eq!(buf.len(), 5);
+        // an incomplete frame at the end is dropped
+        assert!(frames(Framing::LengthPrefixed, &mut buf, true).is_empty());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn raw_takes_everything() {
+        let mut buf = b"a\nb".to_vec();
+        assert_eq!(frames(Framing::Raw, &mut buf, false), ["a\nb"]);
+        assert!(frames(Framing::Raw, &mut buf, true).is_empty());
+    }
+
+    #[test]
+    fn exit_waits_for_the_output_of_children() {
+        let mut command = Command::new("sh");
+        // the shell exits first, its child still holds stdout
+        command.arg("-c").arg("printf 'one\\n'; (sleep 0.1; printf two) &");
+        let port = open(spawn(command).unwrap()).unwrap();
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut d = Dispatcher::new();
+        let owner = d.spawn({
+            let log = log.clone();
+            dispatcher!{
+                Output, o => log.borrow_mut().push(String::from_utf8(o.data).unwrap()),
+                Exit, e => {
+                    log.borrow_mut().push(format!("exit {:?}", e.code));
+                    exit!("exited")
+                }
+            }
+        });
+        d.spawn2(Box::new(move |id| server(id, port, Framing::Line, owner)));
+        d.run();
+        assert_eq!(*log.borrow(), ["one", "two", "exit Some(0)"]);
+    }
+
+    #[test]
+    fn the_program_is_killed_when_the_owner_terminates() {
+        let port = open(spawn(Command::new("cat")).unwrap()).unwrap();
+
+        let mut d = Dispatcher::new();
+        let owner = d.spawn(dispatcher!{ Kill, _ => done!() });
+        let port = d.spawn2(Box::new(move |id| server(id, port, Framing::Raw, owner)));
+        let watcher = d.spawn(dispatcher!{ Down, _ => exit!("port ended") });
+        d.monitor(watcher, port);
+        d.send(owner, Envelope::pack(Kill));
+        d.run();
+    }
+}
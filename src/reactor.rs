@@ -0,0 +1,263 @@
+//! The part of a dispatcher that waits for file descriptors
+//!
+//! Processes register file descriptors with the reactor of the dispatcher they run on
+//! and receive a `WakeUp` when one becomes ready.
+//! `EPoll` and `Uring` are the real implementations, `FakeReactor` is driven by hand in tests.
+
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::ops::Deref;
+use std::time::Duration;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::dispatch::Cid;
+use crate::message::Envelope;
+use crate::sys::{self, Errno, epoll::Flags};
+
+const ENOENT: Errno = libc::ENOENT as Errno;
+
+/// Readiness is described with the epoll flags, whatever the implementation.
+pub trait Reactor: AsRawFd {
+    /// start watching `fd`. `owner` receives a `WakeUp` when `fd` becomes ready for `flags`.
+    /// Fails if `fd` is closed or can't be polled, like a regular file.
+    fn register(&self, fd: RawFd, owner: Cid, flags: Flags) -> Result<Token, Errno>;
+
+    /// like `register` for reading, for a file descriptor that reads as an 8 byte counter,
+    /// like a timerfd or an eventfd. The reactor reads the counter and the `WakeUp`s carry its value.
+    fn register_counter(&self, fd: RawFd, owner: Cid) -> Result<Token, Errno>;
+
+    /// change the events we are interested in, or re-arm a `OneShot` registration
+    fn modify(&self, fd: RawFd, token: Token, flags: Flags) -> Result<(), Errno>;
+
+    fn deregister(&self, fd: RawFd, token: Token) -> Result<(), Errno>;
+
+    /// wait up to `timeout` (forever if None) and append the messages for the processes
+    /// that have to be woken up to `ready`
+    fn wait(&self, ready: &mut Vec<(Cid, Envelope)>, timeout: Option<Duration>) -> Result<(), Errno>;
+
+    /// make the next `wait` return immediately, even if nothing is ready.
+    /// Only for the thread that runs the dispatcher: a `wait` that is already blocked is not interrupted.
+    /// Other threads wake a process with an `eventfd::Notifier`.
+    fn wake(&self);
+}
+
+thread_local! {
+    /// the reactor of the dispatcher that is currently running on this thread
+    static CURRENT: RefCell<Option<Rc<dyn Reactor>>> = RefCell::new(None);
+}
+
+/// makes `reactor` the target of `register` until dropped
+pub(crate) struct Enter {
+    prev: Option<Rc<dyn Reactor>>
+}
+impl Drop for Enter {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|c| *c.borrow_mut() = prev);
+    }
+}
+pub(crate) fn enter(reactor: &Rc<dyn Reactor>) -> Enter {
+    let prev = CURRENT.with(|c| c.borrow_mut().replace(reactor.clone()));
+    Enter { prev }
+}
+
+/// the reactor of the dispatcher that is currently running.
+/// Panics when called outside of a dispatcher.
+pub fn current() -> Rc<dyn Reactor> {
+    CURRENT.with(|c| c.borrow().clone()).expect("not running inside a dispatcher")
+}
+
+/// identifies a registration, so a process that registered several file descriptors
+/// can tell which one is ready. Tokens are not reused.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub struct Token(pub(crate) u64);
+
+/// sent to the owner of a registration when its file descriptor is ready
+#[derive(Copy, Clone, Debug)]
+pub struct WakeUp {
+    token: Token,
+    flags: Flags,
+    count: u64
+}
+impl WakeUp {
+    pub fn new(token: Token, flags: Flags) -> WakeUp {
+        WakeUp { token, flags, count: 1 }
+    }
+    pub fn with_count(token: Token, flags: Flags, count: u64) -> WakeUp {
+        WakeUp { token, flags, count }
+    }
+    /// the registration that is ready
+    pub fn token(&self) -> Token {
+        self.token
+    }
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+    /// the value read from a counter registration, like the number of timer expirations. 1 for others.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+/// a file descriptor registered with a reactor. The registration is removed when this is dropped.
+pub struct Registered<F: AsRawFd> {
+    // only None after `unregister`
+    inner: Option<F>,
+    reactor: Rc<dyn Reactor>,
+    token: Token
+}
+impl<F: AsRawFd> Deref for Registered<F> {
+    type Target = F;
+    fn deref(&self) -> &F {
+        self.inner.as_ref().unwrap()
+    }
+}
+impl<F: AsRawFd> Drop for Registered<F> {
+    fn drop(&mut self) {
+        if let Some(ref f) = self.inner {
+            // runs before `f` is dropped, so the fd is still open
+            let _ = self.reactor.deregister(f.as_raw_fd(), self.token);
+        }
+    }
+}
+impl<F: AsRawFd> Registered<F> {
+    /// the token carried by `WakeUp`s for this registration
+    pub fn token(&self) -> Token {
+        self.token
+    }
+    /// change the events we are interested in, or re-arm a `OneShot` registration
+    pub fn modify(&self, flags: Flags) -> Result<(), Errno> {
+        self.reactor.modify(self.as_raw_fd(), self.token, flags)
+    }
+    pub fn unregister(mut self) -> F {
+        let f = self.inner.take().unwrap();
+        if let Err(e) = self.reactor.deregister(f.as_raw_fd(), self.token) {
+            error!("reactor: deregister {} -> {}", f.as_raw_fd(), e);
+        }
+        f
+    }
+}
+
+/// register `f` with `reactor`. `owner` receives a `WakeUp` when `f` becomes ready for `flags`.
+///
+/// Registrations are level triggered unless `flags` contains `EdgeTriggered`, which the io_uring backend rejects with `EINVAL`.
+/// With `OneShot`, the registration is disabled after the first event until it is re-armed with `modify`.
+/// If that fails, `f` is dropped.
+pub fn register_with<F: AsRawFd>(reactor: &Rc<dyn Reactor>, f: F, owner: Cid, flags: Flags) -> Result<Registered<F>, Errno> {
+    let token = reactor.register(f.as_raw_fd(), owner, flags)?;
+    Ok(Registered { inner: Some(f), reactor: reactor.clone(), token })
+}
+
+/// register `f` with the reactor of the dispatcher that is currently running.
+/// This is what processes use while they are being spawned or run.
+pub fn register<F: AsRawFd>(f: F, owner: Cid, flags: Flags) -> Result<Registered<F>, Errno> {
+    register_with(&current(), f, owner, flags)
+}
+
+/// register the counter `f` with the reactor of the dispatcher that is currently running.
+/// See `Reactor::register_counter`.
+pub fn register_counter<F: AsRawFd>(f: F, owner: Cid) -> Result<Registered<F>, Errno> {
+    let reactor = current();
+    let token = reactor.register_counter(f.as_raw_fd(), owner)?;
+    Ok(Registered { inner: Some(f), reactor, token })
+}
+
+/// read the counter of a timerfd or eventfd. None if it is zero.
+pub(crate) fn read_counter(fd: RawFd) -> Option<u64> {
+    let mut buf = [0u8; 8];
+    match unsafe { sys::read(fd, &mut buf) } {
+        Ok(8) => Some(u64::from_ne_bytes(buf)),
+        _ => None
+    }
+}
+
+/// a reactor that never touches the kernel.
+/// Tests mark file descriptors ready with `set_ready`, and the processes that registered them
+/// get a `WakeUp` the next time the dispatcher polls. Each `set_ready` is delivered once.
+pub struct FakeReactor {
+    /// fd, owner, flags, and false once a `OneShot` registration fired
+    registrations: RefCell<HashMap<Token, (RawFd, Cid, Flags, bool)>>,
+    ready: RefCell<Vec<(RawFd, Flags)>>,
+    next_token: Cell<u64>,
+    wakes: Cell<usize>,
+}
+impl FakeReactor {
+    pub fn new() -> FakeReactor {
+        FakeReactor {
+            registrations: RefCell::new(HashMap::new()),
+            ready: RefCell::new(Vec::new()),
+            next_token: Cell::new(1),
+            wakes: Cell::new(0),
+        }
+    }
+
+    /// make `fd` ready for `flags`
+    pub fn set_ready(&self, fd: RawFd, flags: Flags) {
+        self.ready.borrow_mut().push((fd, flags));
+    }
+
+    /// current registrations as (token, fd, owner, flags), oldest first
+    pub fn registrations(&self) -> Vec<(Token, RawFd, Cid, Flags)> {
+        let mut r: Vec<_> = self.registrations.borrow().iter()
+            .map(|(&token, &(fd, owner, flags, _))| (token, fd, owner, flags))
+            .collect();
+        r.sort_by_key(|r| r.0 .0);
+        r
+    }
+
+    /// how often `wake` was called
+    pub fn wakes(&self) -> usize {
+        self.wakes.get()
+    }
+}
+impl Reactor for FakeReactor {
+    fn register(&self, fd: RawFd, owner: Cid, flags: Flags) -> Result<Token, Errno> {
+        let token = Token(self.next_token.get());
+        self.next_token.set(token.0 + 1);
+        self.registrations.borrow_mut().insert(token, (fd, owner, flags, true));
+        Ok(token)
+    }
+    /// the `WakeUp`s have a count of 1
+    fn register_counter(&self, fd: RawFd, owner: Cid) -> Result<Token, Errno> {
+        self.register(fd, owner, Flags::In)
+    }
+    fn modify(&self, _fd: RawFd, token: Token, flags: Flags) -> Result<(), Errno> {
+        let mut registrations = self.registrations.borrow_mut();
+        let reg = registrations.get_mut(&token).ok_or(ENOENT)?;
+        *reg = (reg.0, reg.1, flags, true);
+        Ok(())
+    }
+    fn deregister(&self, _fd: RawFd, token: Token) -> Result<(), Errno> {
+        self.registrations.borrow_mut().remove(&token).map(|_| ()).ok_or(ENOENT)
+    }
+    /// never blocks, `timeout` is ignored
+    fn wait(&self, ready: &mut Vec<(Cid, Envelope)>, _timeout: Option<Duration>) -> Result<(), Errno> {
+        let mut registrations = self.registrations.borrow_mut();
+        let mut tokens: Vec<_> = registrations.keys().cloned().collect();
+        tokens.sort_by_key(|t| t.0);
+        for (fd, flags) in self.ready.borrow_mut().drain(..) {
+            for token in &tokens {
+                let (reg_fd, owner, interest, ref mut armed) = registrations.get_mut(token).unwrap();
+                let flags = flags & (*interest | Flags::Err | Flags::Hup);
+                if *reg_fd != fd || flags.is_empty() || !*armed {
+                    continue;
+                }
+                ready.push((*owner, Envelope::pack(WakeUp::new(*token, flags))));
+                // like epoll, errors and hang ups are not reported either until it is re-armed
+                if interest.contains(Flags::OneShot) {
+                    *armed = false;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn wake(&self) {
+        self.wakes.set(self.wakes.get() + 1);
+    }
+}
+impl AsRawFd for FakeReactor {
+    /// there is none, -1 is ignored by `poll`
+    fn as_raw_fd(&self) -> RawFd {
+        -1
+    }
+}
@@ -0,0 +1,160 @@
+//! Unix signals as messages
+//!
+//! ```ignore
+//! let main = d.spawn(dispatcher!{
+//!     Signal, s => match s.signo {
+//!         signals::SIGTERM => exit!(0, "terminated"),
+//!         _ => reload_config()
+//!     }
+//! });
+//! d.spawn2(Box::new(move |id| signals::server(id, &[signals::SIGTERM, signals::SIGHUP], main)));
+//! ```
+//!
+//! The signals are blocked on the thread that spawns the server and read from a signalfd.
+//! A signal that is not blocked on another thread may still be delivered there,
+//! so start the server before other threads: they inherit the mask.
+
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::cell::RefCell;
+use std::mem;
+use crate::prelude::*;
+use crate::reactor::{self, WakeUp};
+use crate::sys::{self, Errno, epoll::Flags, signal::{self, SigSet, SignalfdSiginfo}};
+
+pub use libc::{SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2, SIGCHLD};
+
+/// a signal, sent to the subscribers of a `server`
+#[derive(Copy, Clone, Debug)]
+pub struct Signal {
+    pub signo: i32,
+
+    /// the process that sent the signal
+    pub pid: u32,
+    pub uid: u32,
+}
+
+/// ask a `server` to send its signals to this process as well
+#[derive(Debug)]
+pub struct Subscribe(pub Cid);
+
+#[derive(Debug)]
+pub struct Unsubscribe(pub Cid);
+
+thread_local! {
+    /// how many `SignalFd`s on this thread use each signal, so only the last one unblocks it
+    static USERS: RefCell<[usize; 64]> = RefCell::new([0; 64]);
+}
+
+/// count one more user of each signal in `set`
+fn retain(set: &SigSet) {
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        for signo in 1 ..= 64 {
+            if set.contains(signo) {
+                users[signo as usize - 1] += 1;
+            }
+        }
+    })
+}
+
+/// count one user less of each signal in `set`. Returns the signals that have none left.
+fn release(set: &SigSet) -> SigSet {
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        let mut unused = SigSet::default();
+        for signo in 1 ..= 64 {
+            if set.contains(signo) {
+                users[signo as usize - 1] -= 1;
+                if users[signo as usize - 1] == 0 {
+                    let _ = unused.add(signo);
+                }
+            }
+        }
+        unused
+    })
+}
+
+/// a signalfd for a set of blocked signals.
+/// They are unblocked again when the last `SignalFd` of this thread that uses them is dropped.
+struct SignalFd {
+    fd: RawFd,
+    set: SigSet,
+}
+impl SignalFd {
+    fn new(signals: &[i32]) -> Result<SignalFd, Errno> {
+        let mut set = SigSet::default();
+        for &signo in signals {
+            set.add(signo)?;
+        }
+        unsafe {
+            signal::sigprocmask(signal::How::Block, &set)?;
+            retain(&set);
+            match signal::signalfd(&set, signal::NONBLOCK | signal::CLOEXEC) {
+                Ok(fd) => Ok(SignalFd { fd, set }),
+                Err(e) => {
+                    let _ = signal::sigprocmask(signal::How::Unblock, &release(&set));
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// the next pending signal, if any
+    fn read(&self) -> Option<Signal> {
+        let mut info = SignalfdSiginfo::default();
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(&mut info as *mut SignalfdSiginfo as *mut u8, mem::size_of::<SignalfdSiginfo>())
+        };
+        match unsafe { sys::read(self.fd, buf) } {
+            Ok(n) if n == buf.len() => Some(Signal {
+                signo: info.ssi_signo as i32,
+                pid: info.ssi_pid,
+                uid: info.ssi_uid,
+            }),
+            _ => None
+        }
+    }
+}
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = sys::close(self.fd);
+            let _ = signal::sigprocmask(signal::How::Unblock, &release(&self.set));
+        }
+    }
+}
+
+/// blocks `signals` and sends a `Signal` to `reciever` and every subscriber when one arrives.
+/// If the signalfd can't be created or registered, the server logs the error and ends.
+pub fn server(id: Cid, signals: &[i32], reciever: Cid) -> GenBox {
+    let fd = SignalFd::new(signals).and_then(|fd| reactor::register(fd, id, Flags::In));
+    let mut subscribers = vec![reciever];
+
+    Box::pin(Box::new(move |_: ResumeArg| {
+        let fd = match fd {
+            Ok(fd) => fd,
+            Err(e) => {
+                error!("signals: signalfd -> {}", e);
+                done!();
+            }
+        };
+        loop {
+            recv!{
+                WakeUp, _ => {
+                    while let Some(signal) = fd.read() {
+                        for i in 0 .. subscribers.len() {
+                            send!(subscribers[i], signal);
+                        }
+                    }
+                },
+                Subscribe, Subscribe(cid) => subscribers.push(cid),
+                Unsubscribe, Unsubscribe(cid) => subscribers.retain(|&s| s != cid)
+            }
+        }
+    }))
+}
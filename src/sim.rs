@@ -0,0 +1,272 @@
+//! Deterministic simulation
+//!
+//! A `SimDispatcher` picks the next process to run with a seeded RNG instead of strict FIFO,
+//! runs timers on virtual time and replaces epoll with in-memory connections.
+//! Messages between two processes still arrive in the order they were sent.
+//! Given the same seed and the same processes, a run replays exactly.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::dispatch::{Dispatcher, Cid};
+use crate::reactor::{WakeUp, Token};
+use crate::message::Envelope;
+use crate::net::{Stream, Watch};
+use crate::sys::{Errno, epoll::Flags};
+
+/// SplitMix64. Good enough to shuffle, and stable across versions so seeds stay valid.
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// scheduler state of a simulated dispatcher
+pub(crate) struct Sim {
+    rng: Rng,
+    network: Network,
+}
+impl Sim {
+    pub fn new(seed: u64, network: Network) -> Sim {
+        Sim { rng: Rng(seed), network }
+    }
+
+    /// processes woken by in-memory connections, in random order
+    pub fn poll(&mut self) -> Vec<(Cid, Envelope)> {
+        let mut ready = self.network.ready.borrow_mut();
+        let mut woken = Vec::with_capacity(ready.len());
+        while ready.len() > 0 {
+            let i = self.rng.below(ready.len());
+            let (cid, token) = ready.swap_remove(i);
+            woken.push((cid, Envelope::pack(WakeUp::new(token, Flags::In))));
+        }
+        woken
+    }
+
+    /// take a random entry from the queue, but never overtake an earlier entry for the same process
+    pub fn pick<K: Copy + PartialEq, T>(&mut self, queue: &mut VecDeque<(K, T)>) -> Option<(K, T)> {
+        if queue.len() == 0 {
+            return None;
+        }
+        let key = queue[self.rng.below(queue.len())].0;
+        let first = queue.iter().position(|e| e.0 == key).unwrap();
+        queue.remove(first)
+    }
+}
+
+/// a dispatcher for tests. See the module documentation.
+pub struct SimDispatcher {
+    dispatcher: Dispatcher,
+    seed: u64,
+    network: Network,
+}
+impl SimDispatcher {
+    pub fn new(seed: u64) -> SimDispatcher {
+        let network = Network::default();
+        SimDispatcher {
+            dispatcher: Dispatcher::simulated(Sim::new(seed, network.clone())),
+            seed,
+            network,
+        }
+    }
+
+    /// use the seed from `EMP_SIM_SEED` if set, otherwise pick one and print it,
+    /// so a failing run can be replayed.
+    pub fn from_env() -> SimDispatcher {
+        let seed = match std::env::var("EMP_SIM_SEED") {
+            Ok(s) => s.parse().expect("EMP_SIM_SEED is not a number"),
+            Err(_) => {
+                let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+                eprintln!("EMP_SIM_SEED={}", seed);
+                seed
+            }
+        };
+        SimDispatcher::new(seed)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// the in-memory network used in place of epoll
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+}
+impl Deref for SimDispatcher {
+    type Target = Dispatcher;
+    fn deref(&self) -> &Dispatcher {
+        &self.dispatcher
+    }
+}
+impl DerefMut for SimDispatcher {
+    fn deref_mut(&mut self) -> &mut Dispatcher {
+        &mut self.dispatcher
+    }
+}
+
+#[derive(Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    closed: bool,
+
+    /// process to wake up when data arrives
+    owner: Option<(Cid, Token)>,
+}
+
+/// creates in-memory connections
+#[derive(Clone, Default)]
+pub struct Network {
+    ready: Rc<RefCell<Vec<(Cid, Token)>>>,
+    last_token: Rc<Cell<u64>>,
+}
+impl Network {
+    /// a connected pair of in-memory streams
+    pub fn pair(&self) -> (SimConnection, SimConnection) {
+        let a = Rc::new(RefCell::new(Buffer::default()));
+        let b = Rc::new(RefCell::new(Buffer::default()));
+        (
+            SimConnection { rx: a.clone(), tx: b.clone(), network: self.clone() },
+            SimConnection { rx: b, tx: a, network: self.clone() }
+        )
+    }
+    fn token(&self) -> Token {
+        self.last_token.set(self.last_token.get() + 1);
+        Token(self.last_token.get())
+    }
+    fn wake(&self, buf: &Buffer) {
+        if let Some(owner) = buf.owner {
+            let mut ready = self.ready.borrow_mut();
+            if !ready.contains(&owner) {
+                ready.push(owner);
+            }
+        }
+    }
+}
+
+/// one end of an in-memory connection. Dropping it closes the connection.
+pub struct SimConnection {
+    rx: Rc<RefCell<Buffer>>,
+    tx: Rc<RefCell<Buffer>>,
+    network: Network,
+}
+impl SimConnection {
+    /// the token carried by `WakeUp`s for this connection, once it is watched
+    pub fn token(&self) -> Option<Token> {
+        self.rx.borrow().owner.map(|(_, token)| token)
+    }
+}
+impl Stream for SimConnection {
+    fn recv_into(&self, buf: &mut Vec<u8>) -> Result<Option<usize>, Errno> {
+        let mut rx = self.rx.borrow_mut();
+        Ok(match rx.data.len() {
+            0 if rx.closed => Some(0),
+            0 => None,
+            n => {
+                buf.extend(rx.data.drain(..));
+                // level triggered, like epoll: the close still needs to be seen
+                if rx.closed {
+                    self.network.wake(&rx);
+                }
+                Some(n)
+            }
+        })
+    }
+    /// fails with `EPIPE` once the other end is dropped
+    fn send(&self, data: &[u8]) -> Result<Option<usize>, Errno> {
+        if self.rx.borrow().closed {
+            return Err(libc::EPIPE as Errno);
+        }
+        let mut tx = self.tx.borrow_mut();
+        tx.data.extend(data);
+        self.network.wake(&tx);
+        Ok(Some(data.len()))
+    }
+}
+impl Watch for SimConnection {
+    type Watched = SimConnection;
+    fn watch(self, id: Cid) -> Result<SimConnection, Errno> {
+        {
+            let mut rx = self.rx.borrow_mut();
+            rx.owner = Some((id, self.network.token()));
+            if rx.data.len() > 0 || rx.closed {
+                self.network.wake(&rx);
+            }
+        }
+        Ok(self)
+    }
+}
+impl Drop for SimConnection {
+    fn drop(&mut self) {
+        let mut tx = self.tx.borrow_mut();
+        tx.closed = true;
+        self.network.wake(&tx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::prelude::*;
+    use super::SimDispatcher;
+
+    #[derive(Debug)]
+    struct Start;
+
+    /// three processes send five numbered messages each to a recorder, in the order it saw them
+    fn run(seed: u64) -> Vec<(u32, u32)> {
+        let mut d = SimDispatcher::new(seed);
+        let log = Rc::new(RefCell::new(vec![]));
+        let recorder = d.spawn({
+            let log = log.clone();
+            dispatcher!{ (u32, u32), m => log.borrow_mut().push(m) }
+        });
+        for i in 0 .. 3u32 {
+            let sender = d.spawn(dispatcher!{
+                Start, _s => for n in 0 .. 5u32 {
+                    send!(recorder, (i, n));
+                }
+            });
+            d.send(sender, Envelope::pack(Start));
+        }
+        d.run_until_idle();
+        let log = log.borrow().clone();
+        log
+    }
+
+    #[test]
+    fn same_seed_replays() {
+        for seed in 0 .. 10 {
+            assert_eq!(run(seed), run(seed));
+        }
+    }
+
+    #[test]
+    fn seeds_change_the_order() {
+        let first = run(0);
+        assert!((1 .. 20).any(|seed| run(seed) != first));
+    }
+
+    #[test]
+    fn keeps_the_order_between_two_processes() {
+        for seed in 0 .. 20 {
+            let log = run(seed);
+            assert_eq!(log.len(), 15);
+            for i in 0 .. 3 {
+                let from_i: Vec<u32> = log.iter().filter(|m| m.0 == i).map(|m| m.1).collect();
+                assert_eq!(from_i, vec![0, 1, 2, 3, 4]);
+            }
+        }
+    }
+}
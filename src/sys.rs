@@ -5,13 +5,44 @@ use libc;
 use std::os::unix::prelude::*;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::mem;
+use std::time::Duration;
 
 pub type Errno = i64;
 
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64
+}
+impl From<Duration> for Timespec {
+    fn from(d: Duration) -> Timespec {
+        Timespec { tv_sec: d.as_secs() as i64, tv_nsec: d.subsec_nanos() as i64 }
+    }
+}
+
 pub unsafe fn close(fd: RawFd) -> Result<(), Errno> {
     syscall!(SYS_close, fd).map(|_| ())
 }
 
+pub unsafe fn read(fd: RawFd, buf: &mut [u8]) -> Result<usize, Errno> {
+    syscall!(SYS_read, fd, buf.as_mut_ptr(), buf.len()).map(|n| n as _)
+}
+
+pub unsafe fn write(fd: RawFd, buf: &[u8]) -> Result<usize, Errno> {
+    syscall!(SYS_write, fd, buf.as_ptr(), buf.len()).map(|n| n as _)
+}
+
+pub unsafe fn set_nonblocking(fd: RawFd) -> Result<(), Errno> {
+    let flags = syscall!(SYS_fcntl, fd, libc::F_GETFL)?;
+    syscall!(SYS_fcntl, fd, libc::F_SETFL, flags | libc::O_NONBLOCK as i64).map(|_| ())
+}
+
+/// a file descriptor that becomes readable when the process `pid` exits
+pub unsafe fn pidfd_open(pid: u32) -> Result<RawFd, Errno> {
+    syscall!(SYS_pidfd_open, pid, 0).map(|n| n as _)
+}
+
 pub mod epoll {
     use super::*;
     
@@ -29,10 +60,12 @@ pub mod epoll {
             const Err            = libc::EPOLLERR as u32;
             const Hup            = libc::EPOLLHUP as u32;
             const EdgeTriggered  = libc::EPOLLET as u32;
+            const OneShot        = libc::EPOLLONESHOT as u32;
         }
     }
     pub enum CtlOp {
         Add = libc::EPOLL_CTL_ADD as isize,
+        Mod = libc::EPOLL_CTL_MOD as isize,
         Del = libc::EPOLL_CTL_DEL as isize
     }
     pub unsafe fn epoll_create() -> Result<RawFd, Errno> {
@@ -58,12 +91,16 @@ pub mod msg {
             const Peek         = libc::MSG_PEEK as u32;
             const Truncate     = libc::MSG_TRUNC as u32;
             const WaitAll      = libc::MSG_WAITALL as u32;
+            const NoSignal     = libc::MSG_NOSIGNAL as u32;
         }
     }
 
     pub unsafe fn recv(fd: RawFd, buf: &mut [u8], flags: Flags) -> Result<usize, Errno> {
         syscall!(SYS_recvfrom, fd, buf.as_ptr(), buf.len(), flags.bits(), 0, 0).map(|n| n as _)
     }
+    pub unsafe fn send(fd: RawFd, buf: &[u8], flags: Flags) -> Result<usize, Errno> {
+        syscall!(SYS_sendto, fd, buf.as_ptr(), buf.len(), flags.bits(), 0, 0).map(|n| n as _)
+    }
 }
 pub mod sock {
     use super::*;
@@ -147,3 +184,354 @@ pub mod sock {
         Ok((fd as i32, A::from_data(addr)))
     }
 }
+
+pub mod signal {
+    use super::*;
+
+    /// the kernel's signal set, one bit per signal
+    #[repr(transparent)]
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct SigSet(pub u64);
+    impl SigSet {
+        /// fails with `EINVAL` unless `signo` is a signal number, from 1 to 64
+        pub fn add(&mut self, signo: i32) -> Result<(), Errno> {
+            if !(1 ..= 64).contains(&signo) {
+                return Err(libc::EINVAL as Errno);
+            }
+            self.0 |= 1 << (signo - 1);
+            Ok(())
+        }
+        pub fn contains(&self, signo: i32) -> bool {
+            (1 ..= 64).contains(&signo) && self.0 & 1 << (signo - 1) != 0
+        }
+    }
+
+    pub enum How {
+        Block = libc::SIG_BLOCK as isize,
+        Unblock = libc::SIG_UNBLOCK as isize
+    }
+
+    pub const NONBLOCK: i32 = libc::SFD_NONBLOCK;
+    pub const CLOEXEC: i32 = libc::SFD_CLOEXEC;
+
+    /// what a signalfd read returns for each signal
+    #[repr(C)]
+    #[derive(Default, Debug)]
+    pub struct SignalfdSiginfo {
+        pub ssi_signo: u32,
+        pub ssi_errno: i32,
+        pub ssi_code: i32,
+        pub ssi_pid: u32,
+        pub ssi_uid: u32,
+        pub ssi_fd: i32,
+        pub ssi_tid: u32,
+        pub ssi_band: u32,
+        pub ssi_overrun: u32,
+        pub ssi_trapno: u32,
+        pub ssi_status: i32,
+        pub ssi_int: i32,
+        pub ssi_ptr: u64,
+        pub ssi_utime: u64,
+        pub ssi_stime: u64,
+        pub ssi_addr: u64,
+        pub ssi_addr_lsb: u16,
+        pub pad2: u16,
+        pub ssi_syscall: i32,
+        pub ssi_call_addr: u64,
+        pub ssi_arch: u32,
+        pub pad: [u8; 28]
+    }
+
+    /// change the signal mask of the calling thread
+    pub unsafe fn sigprocmask(how: How, set: &SigSet) -> Result<(), Errno> {
+        syscall!(SYS_rt_sigprocmask, how, set as *const SigSet, 0, mem::size_of::<SigSet>()).map(|_| ())
+    }
+    pub unsafe fn signalfd(set: &SigSet, flags: i32) -> Result<RawFd, Errno> {
+        syscall!(SYS_signalfd4, -1, set as *const SigSet, mem::size_of::<SigSet>(), flags).map(|n| n as _)
+    }
+}
+
+pub mod inotify {
+    use super::*;
+    use std::ffi::CStr;
+
+    bitflags! {
+        pub struct Mask: u32 {
+            const Modify         = libc::IN_MODIFY;
+            const MovedFrom      = libc::IN_MOVED_FROM;
+            const MovedTo        = libc::IN_MOVED_TO;
+            const Create         = libc::IN_CREATE;
+            const Delete         = libc::IN_DELETE;
+            const DeleteSelf     = libc::IN_DELETE_SELF;
+            const MoveSelf       = libc::IN_MOVE_SELF;
+            const QueueOverflow  = libc::IN_Q_OVERFLOW;
+            const Ignored        = libc::IN_IGNORED;
+            const IsDir          = libc::IN_ISDIR;
+        }
+    }
+
+    /// header of each event, followed by `len` bytes of NUL padded name
+    #[repr(C)]
+    pub struct Event {
+        pub wd: i32,
+        pub mask: u32,
+        pub cookie: u32,
+        pub len: u32
+    }
+
+    pub unsafe fn init() -> Result<RawFd, Errno> {
+        syscall!(SYS_inotify_init1, libc::IN_NONBLOCK | libc::IN_CLOEXEC).map(|n| n as _)
+    }
+    pub unsafe fn add_watch(fd: RawFd, path: &CStr, mask: Mask) -> Result<i32, Errno> {
+        syscall!(SYS_inotify_add_watch, fd, path.as_ptr(), mask.bits()).map(|n| n as _)
+    }
+    pub unsafe fn rm_watch(fd: RawFd, wd: i32) -> Result<(), Errno> {
+        syscall!(SYS_inotify_rm_watch, fd, wd).map(|_| ())
+    }
+}
+
+pub mod eventfd {
+    use super::*;
+
+    bitflags! {
+        pub struct Flags: i32 {
+            const NonBlock     = libc::EFD_NONBLOCK;
+            const Semaphore    = libc::EFD_SEMAPHORE;
+            const CloseOnExec  = libc::EFD_CLOEXEC;
+        }
+    }
+    pub unsafe fn eventfd(initval: u32, flags: Flags) -> Result<RawFd, Errno> {
+        syscall!(SYS_eventfd2, initval, flags.bits()).map(|n| n as _)
+    }
+}
+
+pub mod timerfd {
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default, Debug)]
+    pub struct Itimerspec {
+        pub interval: Timespec,
+        pub value: Timespec
+    }
+
+    /// a non-blocking timerfd on the monotonic clock
+    pub unsafe fn create() -> Result<RawFd, Errno> {
+        syscall!(SYS_timerfd_create, libc::CLOCK_MONOTONIC, libc::TFD_NONBLOCK | libc::TFD_CLOEXEC).map(|n| n as _)
+    }
+    pub unsafe fn settime(fd: RawFd, spec: &Itimerspec) -> Result<(), Errno> {
+        syscall!(SYS_timerfd_settime, fd, 0, spec as *const Itimerspec, 0).map(|_| ())
+    }
+}
+
+pub mod uring {
+    use super::*;
+
+    pub const OFF_SQ_RING: i64 = 0;
+    pub const OFF_CQ_RING: i64 = 0x8000000;
+    pub const OFF_SQES: i64 = 0x10000000;
+
+    pub const ENTER_GETEVENTS: u32 = 1;
+    pub const REGISTER_BUFFERS: u32 = 0;
+
+    #[repr(u8)]
+    #[derive(Copy, Clone, Debug)]
+    pub enum Op {
+        Nop = 0,
+        ReadFixed = 4,
+        WriteFixed = 5,
+        PollAdd = 6,
+        PollRemove = 7,
+        Timeout = 11,
+        TimeoutRemove = 12,
+        Accept = 13,
+    }
+
+    #[repr(C)]
+    #[derive(Default, Debug)]
+    pub struct SqRingOffsets {
+        pub head: u32,
+        pub tail: u32,
+        pub ring_mask: u32,
+        pub ring_entries: u32,
+        pub flags: u32,
+        pub dropped: u32,
+        pub array: u32,
+        pub resv1: u32,
+        pub resv2: u64
+    }
+
+    #[repr(C)]
+    #[derive(Default, Debug)]
+    pub struct CqRingOffsets {
+        pub head: u32,
+        pub tail: u32,
+        pub ring_mask: u32,
+        pub ring_entries: u32,
+        pub overflow: u32,
+        pub cqes: u32,
+        pub flags: u32,
+        pub resv1: u32,
+        pub resv2: u64
+    }
+
+    #[repr(C)]
+    #[derive(Default, Debug)]
+    pub struct Params {
+        pub sq_entries: u32,
+        pub cq_entries: u32,
+        pub flags: u32,
+        pub sq_thread_cpu: u32,
+        pub sq_thread_idle: u32,
+        pub features: u32,
+        pub wq_fd: u32,
+        pub resv: [u32; 3],
+        pub sq_off: SqRingOffsets,
+        pub cq_off: CqRingOffsets
+    }
+
+    /// submission queue entry
+    #[repr(C)]
+    #[derive(Default, Debug)]
+    pub struct Sqe {
+        pub opcode: u8,
+        pub flags: u8,
+        pub ioprio: u16,
+        pub fd: i32,
+        pub off: u64,
+        pub addr: u64,
+        pub len: u32,
+        pub op_flags: u32,
+        pub user_data: u64,
+        pub buf_index: u16,
+        pub personality: u16,
+        pub splice_fd_in: i32,
+        pub pad: [u64; 2]
+    }
+
+    /// completion queue entry
+    #[repr(C)]
+    #[derive(Copy, Clone, Default, Debug)]
+    pub struct Cqe {
+        pub user_data: u64,
+        pub res: i32,
+        pub flags: u32
+    }
+
+
+    pub unsafe fn setup(entries: u32, params: &mut Params) -> Result<RawFd, Errno> {
+        syscall!(SYS_io_uring_setup, entries, params as *mut Params).map(|n| n as _)
+    }
+    pub unsafe fn enter(fd: RawFd, to_submit: u32, min_complete: u32, flags: u32) -> Result<u32, Errno> {
+        syscall!(SYS_io_uring_enter, fd, to_submit, min_complete, flags, 0, 0).map(|n| n as _)
+    }
+    pub unsafe fn register_buffers(fd: RawFd, iovecs: &[libc::iovec]) -> Result<(), Errno> {
+        syscall!(SYS_io_uring_register, fd, REGISTER_BUFFERS, iovecs.as_ptr(), iovecs.len()).map(|_| ())
+    }
+    pub unsafe fn mmap(fd: RawFd, len: usize, offset: i64) -> Result<*mut u8, Errno> {
+        syscall!(SYS_mmap, 0, len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset).map(|p| p as _)
+    }
+    pub unsafe fn munmap(ptr: *mut u8, len: usize) -> Result<(), Errno> {
+        syscall!(SYS_munmap, ptr, len).map(|_| ())
+    }
+}
+
+pub mod fs {
+    use super::*;
+    use std::ffi::CStr;
+
+    pub const O_DIRECT: i32 = libc::O_DIRECT;
+    pub const O_DIRECTORY: i32 = libc::O_DIRECTORY;
+    pub const O_PATH: i32 = libc::O_PATH;
+
+    pub unsafe fn open(path: &CStr, flags: i32, mode: u32) -> Result<RawFd, Errno> {
+        syscall!(SYS_openat, libc::AT_FDCWD, path.as_ptr(), flags | libc::O_CLOEXEC, mode).map(|n| n as _)
+    }
+    pub unsafe fn openat(dirfd: RawFd, path: &CStr, flags: i32, mode: u32) -> Result<RawFd, Errno> {
+        syscall!(SYS_openat, dirfd, path.as_ptr(), flags | libc::O_CLOEXEC, mode).map(|n| n as _)
+    }
+    pub unsafe fn fstat(fd: RawFd, stat: &mut libc::stat) -> Result<(), Errno> {
+        syscall!(SYS_fstat, fd, stat as *mut libc::stat).map(|_| ())
+    }
+
+    /// fail with EXDEV if the path leaves the directory, through `..`, a symlink or an absolute path
+    pub const RESOLVE_BENEATH: u64 = 0x08;
+    pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+
+    #[repr(C)]
+    #[derive(Default, Debug)]
+    pub struct OpenHow {
+        pub flags: u64,
+        pub mode: u64,
+        pub resolve: u64
+    }
+    pub unsafe fn openat2(dirfd: RawFd, path: &CStr, how: &OpenHow) -> Result<RawFd, Errno> {
+        syscall!(SYS_openat2, dirfd, path.as_ptr(), how as *const OpenHow, mem::size_of::<OpenHow>()).map(|n| n as _)
+    }
+    /// a new descriptor for the same file, closed on exec
+    pub unsafe fn dup(fd: RawFd) -> Result<RawFd, Errno> {
+        syscall!(SYS_fcntl, fd, libc::F_DUPFD_CLOEXEC, 0).map(|n| n as _)
+    }
+}
+
+pub mod aio {
+    use super::*;
+
+    pub type ContextId = u64;
+
+    /// the completion is signaled on `Iocb::resfd`
+    pub const IOCB_FLAG_RESFD: u32 = 1;
+
+    #[repr(u16)]
+    #[derive(Copy, Clone, Debug)]
+    pub enum Op {
+        Pread = 0,
+        Pwrite = 1,
+        Fsync = 2,
+        Fdsync = 3,
+        Noop = 6,
+    }
+
+    /// I/O control block, as laid out on little endian machines
+    #[repr(C)]
+    #[derive(Default, Debug)]
+    pub struct Iocb {
+        pub data: u64,
+        pub key: u32,
+        pub rw_flags: i32,
+        pub opcode: u16,
+        pub reqprio: i16,
+        pub fd: u32,
+        pub buf: u64,
+        pub nbytes: u64,
+        pub offset: i64,
+        pub reserved2: u64,
+        pub flags: u32,
+        pub resfd: u32
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default, Debug)]
+    pub struct Event {
+        /// `Iocb::data` of the request
+        pub data: u64,
+        /// address of the `Iocb`
+        pub obj: u64,
+        pub res: i64,
+        pub res2: i64
+    }
+
+    pub unsafe fn setup(nr_events: u32, ctx: &mut ContextId) -> Result<(), Errno> {
+        syscall!(SYS_io_setup, nr_events, ctx as *mut ContextId).map(|_| ())
+    }
+    pub unsafe fn destroy(ctx: ContextId) -> Result<(), Errno> {
+        syscall!(SYS_io_destroy, ctx).map(|_| ())
+    }
+    pub unsafe fn submit(ctx: ContextId, iocbs: &[*const Iocb]) -> Result<usize, Errno> {
+        syscall!(SYS_io_submit, ctx, iocbs.len(), iocbs.as_ptr()).map(|n| n as _)
+    }
+    pub unsafe fn getevents(ctx: ContextId, min: usize, events: &mut [Event], timeout: Option<&Timespec>) -> Result<usize, Errno> {
+        let timeout = timeout.map(|t| t as *const Timespec).unwrap_or(std::ptr::null());
+        syscall!(SYS_io_getevents, ctx, min, events.len(), events.as_mut_ptr(), timeout).map(|n| n as _)
+    }
+}
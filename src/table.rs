@@ -0,0 +1,510 @@
+//! Tables shared by the processes of a dispatcher, modeled on Erlang's ETS
+//!
+//! ```ignore
+//! // in the owner
+//! let sessions = Table::<u64, Session>::new("sessions", Kind::Set, Access::Public)?;
+//! // in any other process of the dispatcher
+//! let sessions = Table::<u64, Session>::named("sessions")?;
+//! sessions.insert(token, session)?;
+//! let session = sessions.get(&token)?;
+//! let expired = sessions.select(|_, s| if s.expires < now { Some(s.id) } else { None })?;
+//! ```
+//!
+//! A table belongs to the process that created it and is deleted when that process terminates.
+//! Reads and writes are plain function calls on the dispatcher thread, no messages are involved.
+//! The callbacks of `select` and `matching` may read the table, but not write to it:
+//! a write from one of them fails with `TableError::Busy`.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::rc::Rc;
+use crate::dispatch::Cid;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// one value per key
+    Set,
+
+    /// any number of values per key, in the order they were inserted.
+    /// Like ETS's duplicate_bag, inserting a value the key already has adds it again.
+    DuplicateBag,
+
+    /// one value per key, scanned in key order
+    OrderedSet,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// only the owner reads and writes
+    Private,
+
+    /// everyone reads, only the owner writes
+    Protected,
+
+    /// everyone reads and writes
+    Public,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TableError {
+    /// there is no table of that name
+    NotFound,
+
+    /// a table of that name already exists
+    NameTaken,
+
+    /// the table exists, with other key or value types
+    WrongType,
+
+    /// the table was deleted, or its owner terminated
+    Deleted,
+
+    /// the access mode does not allow the calling process to do this
+    AccessDenied,
+
+    /// called from a callback that is scanning the table, like a write from `select`
+    Busy,
+}
+
+thread_local! {
+    /// the tables of the dispatcher that is currently running on this thread
+    static CURRENT: RefCell<Option<Rc<Tables>>> = RefCell::new(None);
+
+    /// the process that is currently running
+    static CALLER: Cell<Option<Cid>> = Cell::new(None);
+}
+
+/// makes `tables` those of `Table::new` and `Table::named` until dropped
+pub(crate) struct Enter {
+    prev: Option<Rc<Tables>>
+}
+impl Drop for Enter {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|c| *c.borrow_mut() = prev);
+    }
+}
+pub(crate) fn enter(tables: &Rc<Tables>) -> Enter {
+    let prev = CURRENT.with(|c| c.borrow_mut().replace(tables.clone()));
+    Enter { prev }
+}
+
+fn current() -> Rc<Tables> {
+    CURRENT.with(|c| c.borrow().clone()).expect("not running inside a dispatcher")
+}
+
+/// makes `cid` the caller of table operations until dropped
+pub(crate) struct Caller {
+    prev: Option<Cid>
+}
+impl Drop for Caller {
+    fn drop(&mut self) {
+        CALLER.with(|c| c.set(self.prev));
+    }
+}
+pub(crate) fn caller(cid: Cid) -> Caller {
+    Caller { prev: CALLER.with(|c| c.replace(Some(cid))) }
+}
+
+enum Store<K, V> {
+    Set(HashMap<K, V>),
+    DuplicateBag(HashMap<K, Vec<V>>),
+    OrderedSet(BTreeMap<K, V>),
+}
+impl<K: Hash + Ord, V> Store<K, V> {
+    fn new(kind: Kind) -> Store<K, V> {
+        match kind {
+            Kind::Set => Store::Set(HashMap::new()),
+            Kind::DuplicateBag => Store::DuplicateBag(HashMap::new()),
+            Kind::OrderedSet => Store::OrderedSet(BTreeMap::new()),
+        }
+    }
+
+    /// call `f` with each key and value until it returns false
+    fn scan(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        match *self {
+            Store::Set(ref map) => for (k, v) in map {
+                if !f(k, v) { return; }
+            },
+            Store::DuplicateBag(ref map) => for (k, values) in map {
+                for v in values {
+                    if !f(k, v) { return; }
+                }
+            },
+            Store::OrderedSet(ref map) => for (k, v) in map {
+                if !f(k, v) { return; }
+            },
+        }
+    }
+}
+
+struct Shared<K, V> {
+    name: String,
+    owner: Cid,
+    access: Access,
+    kind: Kind,
+
+    /// None once deleted
+    store: RefCell<Option<Store<K, V>>>,
+}
+
+/// a table of any type, as the registry sees it
+trait AnyTable {
+    fn owner(&self) -> Cid;
+    fn delete(&self);
+    fn as_any(self: Rc<Self>) -> Rc<dyn Any>;
+}
+impl<K: 'static, V: 'static> AnyTable for Shared<K, V> {
+    fn owner(&self) -> Cid {
+        self.owner
+    }
+    fn delete(&self) {
+        // the values are dropped after the borrow ends, in case they use the table
+        let store = self.store.borrow_mut().take();
+        drop(store);
+    }
+    fn as_any(self: Rc<Self>) -> Rc<dyn Any> {
+        self
+    }
+}
+
+/// the named tables of a dispatcher
+pub(crate) struct Tables {
+    named: RefCell<HashMap<String, Rc<dyn AnyTable>>>,
+}
+impl Tables {
+    pub(crate) fn new() -> Tables {
+        Tables { named: RefCell::new(HashMap::new()) }
+    }
+
+    /// delete the tables `owner` created
+    pub(crate) fn owner_exited(&self, owner: Cid) {
+        let mut gone = vec![];
+        self.named.borrow_mut().retain(|_, t| {
+            if t.owner() == owner {
+                gone.push(t.clone());
+                false
+            } else {
+                true
+            }
+        });
+        for table in gone {
+            table.delete();
+        }
+    }
+
+    fn remove(&self, name: &str) -> Option<Rc<dyn AnyTable>> {
+        self.named.borrow_mut().remove(name)
+    }
+}
+
+/// a handle to a table with keys `K` and values `V`
+pub struct Table<K, V> {
+    shared: Rc<Shared<K, V>>,
+}
+impl<K, V> Clone for Table<K, V> {
+    fn clone(&self) -> Table<K, V> {
+        Table { shared: self.shared.clone() }
+    }
+}
+impl<K, V> std::fmt::Debug for Table<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Table({:?}, {:?}, owner: {:?})", self.shared.name, self.shared.kind, self.shared.owner)
+    }
+}
+impl<K: Hash + Ord + Clone + 'static, V: Clone + 'static> Table<K, V> {
+    /// create a table owned by the calling process.
+    /// Panics when called outside of a process.
+    pub fn new(name: impl Into<String>, kind: Kind, access: Access) -> Result<Table<K, V>, TableError> {
+        let owner = CALLER.with(|c| c.get()).expect("Table::new called outside of a process");
+        let name = name.into();
+        let tables = current();
+        let mut named = tables.named.borrow_mut();
+        if named.contains_key(&name) {
+            return Err(TableError::NameTaken);
+        }
+        let shared = Rc::new(Shared {
+            name: name.clone(),
+            owner,
+            access,
+            kind,
+            store: RefCell::new(Some(Store::new(kind))),
+        });
+        named.insert(name, shared.clone());
+        Ok(Table { shared })
+    }
+
+    /// the table called `name`
+    pub fn named(name: &str) -> Result<Table<K, V>, TableError> {
+        let table = current().named.borrow().get(name).cloned().ok_or(TableError::NotFound)?;
+        match table.as_any().downcast::<Shared<K, V>>() {
+            Ok(shared) => Ok(Table { shared }),
+            Err(_) => Err(TableError::WrongType)
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.shared.name
+    }
+    pub fn owner(&self) -> Cid {
+        self.shared.owner
+    }
+    pub fn kind(&self) -> Kind {
+        self.shared.kind
+    }
+
+    fn is_owner(&self) -> bool {
+        CALLER.with(|c| c.get()) == Some(self.shared.owner)
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Store<K, V>) -> T) -> Result<T, TableError> {
+        if self.shared.access == Access::Private && !self.is_owner() {
+            return Err(TableError::AccessDenied);
+        }
+        match *self.shared.store.try_borrow().map_err(|_| TableError::Busy)? {
+            Some(ref store) => Ok(f(store)),
+            None => Err(TableError::Deleted)
+        }
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut Store<K, V>) -> T) -> Result<T, TableError> {
+        if self.shared.access != Access::Public && !self.is_owner() {
+            return Err(TableError::AccessDenied);
+        }
+        match *self.shared.store.try_borrow_mut().map_err(|_| TableError::Busy)? {
+            Some(ref mut store) => Ok(f(store)),
+            None => Err(TableError::Deleted)
+        }
+    }
+
+    /// insert `value` under `key`. In a set it replaces the value, in a bag it is added.
+    pub fn insert(&self, key: K, value: V) -> Result<(), TableError> {
+        self.write(|store| match *store {
+            Store::Set(ref mut map) => { map.insert(key, value); }
+            Store::DuplicateBag(ref mut map) => map.entry(key).or_insert_with(Vec::new).push(value),
+            Store::OrderedSet(ref mut map) => { map.insert(key, value); }
+        })
+    }
+
+    /// the value of `key`, the first one in a bag
+    pub fn get(&self, key: &K) -> Result<Option<V>, TableError> {
+        self.read(|store| match *store {
+            Store::Set(ref map) => map.get(key).cloned(),
+            Store::DuplicateBag(ref map) => map.get(key).and_then(|values| values.first().cloned()),
+            Store::OrderedSet(ref map) => map.get(key).cloned(),
+        })
+    }
+
+    /// all values of `key`
+    pub fn lookup(&self, key: &K) -> Result<Vec<V>, TableError> {
+        self.read(|store| match *store {
+            Store::Set(ref map) => map.get(key).cloned().into_iter().collect(),
+            Store::DuplicateBag(ref map) => map.get(key).cloned().unwrap_or_default(),
+            Store::OrderedSet(ref map) => map.get(key).cloned().into_iter().collect(),
+        })
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool, TableError> {
+        self.read(|store| match *store {
+            Store::Set(ref map) => map.contains_key(key),
+            Store::DuplicateBag(ref map) => map.contains_key(key),
+            Store::OrderedSet(ref map) => map.contains_key(key),
+        })
+    }
+
+    /// remove `key` and all of its values
+    pub fn remove(&self, key: &K) -> Result<Vec<V>, TableError> {
+        self.write(|store| match *store {
+            Store::Set(ref mut map) => map.remove(key).into_iter().collect(),
+            Store::DuplicateBag(ref mut map) => map.remove(key).unwrap_or_default(),
+            Store::OrderedSet(ref mut map) => map.remove(key).into_iter().collect(),
+        })
+    }
+
+    /// the number of values
+    pub fn len(&self) -> Result<usize, TableError> {
+        self.read(|store| match *store {
+            Store::Set(ref map) => map.len(),
+            Store::DuplicateBag(ref map) => map.values().map(|values| values.len()).sum(),
+            Store::OrderedSet(ref map) => map.len(),
+        })
+    }
+
+    /// the keys and values `pred` accepts
+    pub fn matching(&self, mut pred: impl FnMut(&K, &V) -> bool) -> Result<Vec<(K, V)>, TableError> {
+        self.select(|k, v| if pred(k, v) { Some((k.clone(), v.clone())) } else { None })
+    }
+
+    /// what `f` returns for each key and value, skipping `None`
+    pub fn select<T>(&self, f: impl FnMut(&K, &V) -> Option<T>) -> Result<Vec<T>, TableError> {
+        self.select_limit(usize::MAX, f)
+    }
+
+    /// like `select`, but stop after `limit` results
+    pub fn select_limit<T>(&self, limit: usize, mut f: impl FnMut(&K, &V) -> Option<T>) -> Result<Vec<T>, TableError> {
+        self.read(|store| {
+            let mut found = vec![];
+            if limit > 0 {
+                store.scan(|k, v| {
+                    found.extend(f(k, v));
+                    found.len() < limit
+                });
+            }
+            found
+        })
+    }
+
+    /// remove the keys and values `pred` accepts, returning how many values were removed
+    pub fn remove_matching(&self, mut pred: impl FnMut(&K, &V) -> bool) -> Result<usize, TableError> {
+        self.write(|store| match *store {
+            Store::Set(ref mut map) => {
+                let before = map.len();
+                map.retain(|k, v| !pred(k, v));
+                before - map.len()
+            }
+            Store::DuplicateBag(ref mut map) => {
+                let mut removed = 0;
+                map.retain(|k, values| {
+                    let before = values.len();
+                    values.retain(|v| !pred(k, v));
+                    removed += before - values.len();
+                    values.len() > 0
+                });
+                removed
+            }
+            Store::OrderedSet(ref mut map) => {
+                let before = map.len();
+                map.retain(|k, v| !pred(k, v));
+                before - map.len()
+            }
+        })
+    }
+
+    /// delete the table. Only the owner can.
+    pub fn delete(self) -> Result<(), TableError> {
+        if !self.is_owner() {
+            return Err(TableError::AccessDenied);
+        }
+        match self.shared.store.try_borrow_mut() {
+            Ok(ref store) if store.is_none() => return Err(TableError::Deleted),
+            Ok(_) => {}
+            Err(_) => return Err(TableError::Busy)
+        }
+        current().remove(&self.shared.name);
+        self.shared.delete();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::prelude::*;
+    use crate::testing::{TestDispatcher, Probe};
+    use super::*;
+
+    #[derive(Debug)]
+    struct Create(&'static str, Access);
+    #[derive(Debug)]
+    struct Insert(&'static str, u32, u32);
+    #[derive(Debug)]
+    struct Get(&'static str, u32);
+
+    /// look up a table and keep the handle
+    #[derive(Debug)]
+    struct Keep(&'static str);
+    #[derive(Debug)]
+    struct GetKept(u32);
+    #[derive(Debug)]
+    struct InsertWhileSelecting(&'static str);
+    #[derive(Debug)]
+    struct Stop;
+
+    /// does what it is told with tables of u32, and sends the results to `probe`
+    fn user(probe: Cid) -> GenBox {
+        Box::pin(Box::new(move |_: ResumeArg| {
+            let mut kept = None;
+            loop {
+                recv!{
+                    Create, Create(name, access) => {
+                        send!(probe, Table::<u32, u32>::new(name, Kind::Set, access).map(|_| ()))
+                    },
+                    Insert, Insert(name, k, v) => {
+                        send!(probe, Table::<u32, u32>::named(name).and_then(|t| t.insert(k, v)))
+                    },
+                    Get, Get(name, k) => {
+                        send!(probe, Table::<u32, u32>::named(name).and_then(|t| t.get(&k)))
+                    },
+                    Keep, Keep(name) => kept = Table::<u32, u32>::named(name).ok(),
+                    GetKept, GetKept(k) => send!(probe, kept.as_ref().unwrap().get(&k)),
+                    InsertWhileSelecting, InsertWhileSelecting(name) => {
+                        let table = Table::<u32, u32>::named(name).unwrap();
+                        let inserted = table.select(|&k, _| Some(table.insert(k + 1, 0)));
+                        send!(probe, inserted)
+                    },
+                    Stop, _ => done!()
+                }
+            }
+        }))
+    }
+
+    fn ask<T: Message + 'static>(d: &mut TestDispatcher, probe: &Probe, to: Cid, msg: impl Message + 'static) -> T {
+        d.send(to, Envelope::pack(msg));
+        d.expect_message(probe, Duration::from_secs(1))
+    }
+
+    #[test]
+    fn access_modes() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let owner = d.spawn(user(probe.cid()));
+        let other = d.spawn(user(probe.cid()));
+        for &(name, access) in &[("private", Access::Private), ("protected", Access::Protected), ("public", Access::Public)] {
+            assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, owner, Create(name, access)), Ok(()));
+            assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, owner, Insert(name, 1, 10)), Ok(()));
+            assert_eq!(ask::<Result<Option<u32>, TableError>>(&mut d, &probe, owner, Get(name, 1)), Ok(Some(10)));
+        }
+
+        assert_eq!(ask::<Result<Option<u32>, TableError>>(&mut d, &probe, other, Get("private", 1)), Err(TableError::AccessDenied));
+        assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, other, Insert("private", 2, 20)), Err(TableError::AccessDenied));
+
+        assert_eq!(ask::<Result<Option<u32>, TableError>>(&mut d, &probe, other, Get("protected", 1)), Ok(Some(10)));
+        assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, other, Insert("protected", 2, 20)), Err(TableError::AccessDenied));
+
+        assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, other, Insert("public", 2, 20)), Ok(()));
+        assert_eq!(ask::<Result<Option<u32>, TableError>>(&mut d, &probe, owner, Get("public", 2)), Ok(Some(20)));
+
+        assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, other, Create("public", Access::Public)), Err(TableError::NameTaken));
+    }
+
+    #[test]
+    fn tables_are_deleted_when_the_owner_exits() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let owner = d.spawn(user(probe.cid()));
+        let other = d.spawn(user(probe.cid()));
+        assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, owner, Create("t", Access::Public)), Ok(()));
+        assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, other, Insert("t", 1, 10)), Ok(()));
+        d.send(other, Envelope::pack(Keep("t")));
+        assert_eq!(ask::<Result<Option<u32>, TableError>>(&mut d, &probe, other, GetKept(1)), Ok(Some(10)));
+
+        d.send(owner, Envelope::pack(Stop));
+        d.run_until_idle();
+        assert_eq!(ask::<Result<Option<u32>, TableError>>(&mut d, &probe, other, GetKept(1)), Err(TableError::Deleted));
+        assert_eq!(ask::<Result<Option<u32>, TableError>>(&mut d, &probe, other, Get("t", 1)), Err(TableError::NotFound));
+    }
+
+    #[test]
+    fn a_write_while_scanning_is_busy() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let owner = d.spawn(user(probe.cid()));
+        assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, owner, Create("t", Access::Public)), Ok(()));
+        assert_eq!(ask::<Result<(), TableError>>(&mut d, &probe, owner, Insert("t", 1, 10)), Ok(()));
+        let inserted = ask::<Result<Vec<Result<(), TableError>>, TableError>>(&mut d, &probe, owner, InsertWhileSelecting("t"));
+        assert_eq!(inserted, Ok(vec![Err(TableError::Busy)]));
+        assert_eq!(ask::<Result<Option<u32>, TableError>>(&mut d, &probe, owner, Get("t", 2)), Ok(None));
+    }
+}
@@ -0,0 +1,255 @@
+//! Helpers to test processes without real IO or a real clock
+//!
+//! ```ignore
+//! let mut d = TestDispatcher::new();
+//! let probe = d.probe();
+//! let p = probe.cid();
+//! let echo = d.spawn(dispatcher!{ u32, n => send!(p, n) });
+//! d.send(echo, Envelope::pack(42u32));
+//! assert_eq!(d.expect_message::<u32>(&probe, Duration::from_secs(1)), 42);
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::time::Duration;
+use std::any::TypeId;
+use crate::dispatch::{Dispatcher, Cid, GenBox, ProcessYield, ResumeArg, ProcessExit};
+use crate::reactor::{WakeUp, Token};
+pub use crate::reactor::FakeReactor;
+use crate::message::{Envelope, Message};
+use crate::sys::epoll::Flags;
+
+/// a process that records every message it receives
+#[derive(Clone)]
+pub struct Probe {
+    cid: Cid,
+    messages: Rc<RefCell<VecDeque<Envelope>>>,
+}
+impl Probe {
+    pub fn cid(&self) -> Cid {
+        self.cid
+    }
+
+    /// number of recorded messages not yet taken by an `expect_*` call
+    pub fn len(&self) -> usize {
+        self.messages.borrow().len()
+    }
+
+    fn pop(&self) -> Option<Envelope> {
+        self.messages.borrow_mut().pop_front()
+    }
+}
+
+fn probe(messages: Rc<RefCell<VecDeque<Envelope>>>) -> GenBox {
+    Box::pin(Box::new(move |_: ResumeArg| {
+        loop {
+            match (yield ProcessYield::Empty) {
+                ResumeArg::Message(envelope) => messages.borrow_mut().push_back(envelope),
+                _ => {}
+            }
+        }
+        #[allow(unreachable_code)]
+        ProcessExit::Done
+    }))
+}
+
+/// a dispatcher with a `FakeReactor` that runs on virtual time.
+/// Time only moves inside `advance`, `expect_message` and `expect_no_message`.
+pub struct TestDispatcher {
+    dispatcher: Dispatcher,
+    reactor: Rc<FakeReactor>,
+}
+impl TestDispatcher {
+    pub fn new() -> TestDispatcher {
+        let reactor = Rc::new(FakeReactor::new());
+        TestDispatcher {
+            dispatcher: Dispatcher::manual(reactor.clone()),
+            reactor,
+        }
+    }
+
+    /// the reactor processes register with
+    pub fn reactor(&self) -> &FakeReactor {
+        &self.reactor
+    }
+
+    /// spawn a new probe process
+    pub fn probe(&mut self) -> Probe {
+        let messages = Rc::new(RefCell::new(VecDeque::new()));
+        let cid = self.dispatcher.spawn(probe(messages.clone()));
+        Probe { cid, messages }
+    }
+
+    /// deliver a `WakeUp` to `cid`, as if the registration `token` became ready
+    pub fn wake_up(&mut self, cid: Cid, token: Token, flags: Flags) {
+        self.dispatcher.send(cid, Envelope::pack(WakeUp::new(token, flags)));
+    }
+
+    /// move virtual time forward by `dt`, running all timers that become due on the way
+    pub fn advance(&mut self, dt: Duration) {
+        let deadline = self.dispatcher.now() + dt;
+        self.run_until(deadline, || false);
+    }
+
+    /// run until `done` returns true or nothing is left to do before `deadline`.
+    fn run_until(&mut self, deadline: Duration, mut done: impl FnMut() -> bool) -> bool {
+        loop {
+            self.dispatcher.run_until_idle();
+            if done() {
+                return true;
+            }
+            if self.dispatcher.poll_events(Some(Duration::from_secs(0))) > 0 {
+                continue;
+            }
+            if !self.dispatcher.advance_timers(deadline) {
+                self.dispatcher.advance_clock(deadline);
+                return false;
+            }
+        }
+    }
+
+    /// wait up to `timeout` for the next message of `probe`, which has to be a `T`.
+    /// panics if no message arrives or it has a different type.
+    pub fn expect_message<T: Message + 'static>(&mut self, probe: &Probe, timeout: Duration) -> T {
+        let deadline = self.dispatcher.now() + timeout;
+        if !self.run_until(deadline, || probe.len() > 0) {
+            panic!("expected a {} within {:?}, got nothing", std::any::type_name::<T>(), timeout);
+        }
+        let envelope = probe.pop().unwrap();
+        if envelope.type_id != TypeId::of::<T>() {
+            panic!("expected a {}, got a {}", std::any::type_name::<T>(), envelope.type_name);
+        }
+        envelope.unpack()
+    }
+
+    /// panics if `probe` receives anything within `timeout`
+    pub fn expect_no_message(&mut self, probe: &Probe, timeout: Duration) {
+        let deadline = self.dispatcher.now() + timeout;
+        if self.run_until(deadline, || probe.len() > 0) {
+            panic!("expected no message, got a {}", probe.pop().unwrap().type_name);
+        }
+    }
+}
+impl Deref for TestDispatcher {
+    type Target = Dispatcher;
+    fn deref(&self) -> &Dispatcher {
+        &self.dispatcher
+    }
+}
+impl DerefMut for TestDispatcher {
+    fn deref_mut(&mut self) -> &mut Dispatcher {
+        &mut self.dispatcher
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::{RawFd, AsRawFd};
+    use std::time::Duration;
+    use crate::prelude::*;
+    use crate::reactor::{self, Reactor, WakeUp};
+    use crate::sys::epoll::Flags;
+    use super::TestDispatcher;
+
+    #[derive(Debug)]
+    struct Stop;
+
+    /// stands in for a file descriptor, the fake reactor never touches it
+    struct Fd(RawFd);
+    impl AsRawFd for Fd {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0
+        }
+    }
+
+    /// sends the flags of each `WakeUp` for `fd` to `probe`
+    fn watcher(id: Cid, fd: RawFd, flags: Flags, probe: Cid) -> GenBox {
+        let fd = reactor::register(Fd(fd), id, flags).unwrap();
+        Box::pin(Box::new(move |_: ResumeArg| {
+            loop {
+                recv!{
+                    WakeUp, w => {
+                        assert_eq!(w.token(), fd.token());
+                        send!(probe, w.flags());
+                    },
+                    Stop, _s => done!()
+                }
+            }
+        }))
+    }
+
+    #[test]
+    fn probe_records_messages() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let p = probe.cid();
+        let echo = d.spawn(dispatcher!{ u32, n => send!(p, n + 1) });
+        d.expect_no_message(&probe, Duration::from_secs(1));
+        d.send(echo, Envelope::pack(41u32));
+        assert_eq!(d.expect_message::<u32>(&probe, Duration::from_secs(1)), 42);
+        assert_eq!(probe.len(), 0);
+    }
+
+    #[test]
+    fn timers_run_on_virtual_time() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let p = probe.cid();
+        let delay = d.spawn(dispatcher!{ u32, n => send_after!(Duration::from_secs(10), p, n) });
+        d.send(delay, Envelope::pack(7u32));
+        d.expect_no_message(&probe, Duration::from_secs(9));
+        assert_eq!(d.now(), Duration::from_secs(9));
+        assert_eq!(d.expect_message::<u32>(&probe, Duration::from_secs(2)), 7);
+        assert_eq!(d.now(), Duration::from_secs(10));
+
+        d.send(delay, Envelope::pack(8u32));
+        d.advance(Duration::from_secs(60));
+        assert_eq!(d.expect_message::<u32>(&probe, Duration::from_secs(0)), 8);
+    }
+
+    #[test]
+    fn fake_reactor_wakes_up_the_owner() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let p = probe.cid();
+        let w = d.spawn2(Box::new(move |id| watcher(id, 7, Flags::In, p)));
+        let registrations = d.reactor().registrations();
+        assert_eq!(registrations.len(), 1);
+        assert_eq!((registrations[0].1, registrations[0].2), (7, w));
+
+        d.reactor().set_ready(7, Flags::In | Flags::Out);
+        assert_eq!(d.expect_message::<Flags>(&probe, Duration::from_secs(1)), Flags::In);
+        d.reactor().set_ready(8, Flags::In);
+        d.expect_no_message(&probe, Duration::from_secs(1));
+
+        let token = registrations[0].0;
+        d.wake_up(w, token, Flags::Hup);
+        assert_eq!(d.expect_message::<Flags>(&probe, Duration::from_secs(1)), Flags::Hup);
+
+        d.send(w, Envelope::pack(Stop));
+        d.run_until_idle();
+        assert!(d.reactor().registrations().is_empty());
+        assert!(d.process_info(w).is_none());
+    }
+
+    #[test]
+    fn one_shot_registrations_fire_once() {
+        let mut d = TestDispatcher::new();
+        let probe = d.probe();
+        let p = probe.cid();
+        d.spawn2(Box::new(move |id| watcher(id, 7, Flags::In | Flags::OneShot, p)));
+        let token = d.reactor().registrations()[0].0;
+
+        d.reactor().set_ready(7, Flags::In);
+        assert_eq!(d.expect_message::<Flags>(&probe, Duration::from_secs(1)), Flags::In);
+        d.reactor().set_ready(7, Flags::In | Flags::Hup);
+        d.reactor().set_ready(7, Flags::Err);
+        d.expect_no_message(&probe, Duration::from_secs(1));
+
+        d.reactor().modify(7, token, Flags::In | Flags::OneShot).unwrap();
+        d.reactor().set_ready(7, Flags::Hup);
+        assert_eq!(d.expect_message::<Flags>(&probe, Duration::from_secs(1)), Flags::Hup);
+    }
+}
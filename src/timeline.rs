@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+use std::path::Path;
+use std::time::Instant;
+use crate::dispatch::Cid;
+
+enum Record {
+    /// a complete span ("X")
+    Span { name: String, tid: u64, start: f64, dur: f64 },
+
+    /// start of a message flow ("s")
+    Sent { id: u64, tid: u64, ts: f64, msg: &'static str },
+
+    /// end of a message flow ("f")
+    Received { id: u64, tid: u64, ts: f64, msg: &'static str, latency: f64 },
+}
+
+/// records scheduling spans and message flows.
+/// The result can be written in Chrome `trace_event` JSON and opened in Perfetto or `chrome://tracing`.
+pub struct Timeline {
+    start: Instant,
+    records: Vec<Record>,
+    /// receiver and send time of the messages not yet received
+    in_flight: HashMap<u64, (Cid, f64)>,
+    next_id: u64,
+
+    /// tid and end of the last span, so flows start inside the span that sent them
+    last_span: (u64, f64),
+}
+
+/// thread id used for events that do not belong to a process
+const DISPATCHER_TID: u64 = 0;
+
+/// index and version of the process, so a reused slot gets a track of its own
+fn tid(cid: Option<Cid>) -> u64 {
+    match cid {
+        Some(cid) => cid.as_ffi(),
+        None => DISPATCHER_TID
+    }
+}
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline {
+            start: Instant::now(),
+            records: vec![],
+            in_flight: HashMap::new(),
+            next_id: 1,
+            last_span: (DISPATCHER_TID, 0.),
+        }
+    }
+
+    /// microseconds since the timeline was started
+    fn micros(&self, t: Instant) -> f64 {
+        t.duration_since(self.start).as_secs_f64() * 1e6
+    }
+
+    pub(crate) fn span(&mut self, name: impl Into<String>, cid: Option<Cid>, start: Instant, end: Instant) {
+        let start = self.micros(start);
+        let dur = self.micros(end) - start;
+        self.last_span = (tid(cid), start + dur);
+        self.records.push(Record::Span { name: name.into(), tid: tid(cid), start, dur });
+    }
+
+    /// returns the flow id to be stored in the envelope
+    pub(crate) fn sent(&mut self, from: Option<Cid>, to: Cid, msg: &'static str) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let tid = tid(from);
+        let ts = match self.last_span {
+            (last, end) if last == tid && tid != DISPATCHER_TID => end,
+            _ => self.micros(Instant::now())
+        };
+        self.in_flight.insert(id, (to, ts));
+        self.records.push(Record::Sent { id, tid, ts, msg });
+        id
+    }
+
+    pub(crate) fn received(&mut self, id: u64, to: Cid, msg: &'static str) {
+        if let Some((_, sent)) = self.in_flight.remove(&id) {
+            let ts = self.micros(Instant::now());
+            self.records.push(Record::Received { id, tid: tid(Some(to)), ts, msg, latency: ts - sent });
+        }
+    }
+
+    /// `cid` terminated, the messages still queued for it are never received
+    pub(crate) fn exited(&mut self, cid: Cid) {
+        self.in_flight.retain(|_, &mut (to, _)| to != cid);
+    }
+
+    /// write all records as a JSON array of trace events
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "[")?;
+        for (i, record) in self.records.iter().enumerate() {
+            if i > 0 {
+                writeln!(w, ",")?;
+            }
+            match *record {
+                Record::Span { ref name, tid, start, dur } => write!(w,
+                    r#"{{"name":"{}","cat":"resume","ph":"X","pid":1,"tid":{},"ts":{:.3},"dur":{:.3}}}"#,
+                    Escaped(name), tid, start, dur
+                )?,
+                Record::Sent { id, tid, ts, msg } => write!(w,
+                    r#"{{"name":"{}","cat":"message","ph":"s","id":{},"pid":1,"tid":{},"ts":{:.3}}}"#,
+                    Escaped(msg), id, tid, ts
+                )?,
+                Record::Received { id, tid, ts, msg, latency } => write!(w,
+                    r#"{{"name":"{}","cat":"message","ph":"f","id":{},"pid":1,"tid":{},"ts":{:.3},"args":{{"latency_us":{:.3}}}}}"#,
+                    Escaped(msg), id, tid, ts, latency
+                )?,
+            }
+        }
+        writeln!(w, "\n]")
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+}
+
+struct Escaped<'a>(&'a str);
+impl<'a> std::fmt::Display for Escaped<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flows_to_an_exited_process_are_dropped() {
+        // slot 1 in two versions, as after a process exited and its slot was reused
+        let old = Cid::from_ffi(1 << 32 | 1);
+        let new = Cid::from_ffi(3 << 32 | 1);
+        assert_ne!(tid(Some(old)), tid(Some(new)));
+
+        let mut timeline = Timeline::new();
+        timeline.sent(None, old, "u32");
+        let id = timeline.sent(None, new, "u32");
+        timeline.exited(old);
+        assert_eq!(timeline.in_flight.len(), 1);
+        timeline.received(id, new, "u32");
+        assert!(timeline.in_flight.is_empty());
+    }
+}
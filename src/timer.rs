@@ -0,0 +1,158 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::time::{Duration, Instant};
+use crate::dispatch::Cid;
+use crate::message::Envelope;
+use crate::sys::{self, Errno, timerfd::{self, Itimerspec}};
+
+const EAGAIN: Errno = libc::EAGAIN as Errno;
+
+/// time as seen by the dispatcher
+pub(crate) enum Clock {
+    /// wall clock, relative to the creation of the dispatcher
+    Real(Instant),
+
+    /// only moves when the dispatcher advances it
+    Virtual(Duration),
+}
+impl Clock {
+    pub fn now(&self) -> Duration {
+        match *self {
+            Clock::Real(start) => start.elapsed(),
+            Clock::Virtual(now) => now
+        }
+    }
+    pub fn advance_to(&mut self, t: Duration) {
+        if let Clock::Virtual(ref mut now) = *self {
+            if t > *now {
+                *now = t;
+            }
+        }
+    }
+}
+
+struct Entry {
+    deadline: Duration,
+    seq: u64,
+    addr: Cid,
+    msg: Envelope,
+}
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Entry {}
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Entry {
+    // timers with the same deadline fire in the order they were set
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+/// messages waiting to be delivered at a later time
+pub(crate) struct Timers {
+    heap: BinaryHeap<Reverse<Entry>>,
+    seq: u64,
+}
+impl Timers {
+    pub fn new() -> Timers {
+        Timers { heap: BinaryHeap::new(), seq: 0 }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+    pub fn insert(&mut self, deadline: Duration, addr: Cid, msg: Envelope) {
+        let seq = self.seq;
+        self.seq += 1;
+        self.heap.push(Reverse(Entry { deadline, seq, addr, msg }));
+    }
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.heap.peek().map(|e| e.0.deadline)
+    }
+    /// remove the next timer if it is due at `now`
+    pub fn pop_due(&mut self, now: Duration) -> Option<(Cid, Envelope)> {
+        match self.heap.peek() {
+            Some(e) if e.0.deadline <= now => {
+                let Reverse(e) = self.heap.pop().unwrap();
+                Some((e.addr, e.msg))
+            }
+            _ => None
+        }
+    }
+}
+
+/// a timerfd, for periodic work that needs more precision than `send_after!`.
+/// Registered with `reactor::register_counter`, its owner gets a `WakeUp`
+/// whose `count` is the number of expirations since the last one:
+///
+/// ```ignore
+/// let tick = reactor::register_counter(Timer::periodic(Duration::from_millis(1))?, id)?;
+/// ```
+pub struct Timer {
+    fd: RawFd,
+}
+impl Timer {
+    /// a timer that is not armed
+    pub fn new() -> Result<Timer, Errno> {
+        let fd = unsafe { timerfd::create()? };
+        Ok(Timer { fd })
+    }
+
+    /// expires once, after `after`
+    pub fn once(after: Duration) -> Result<Timer, Errno> {
+        let timer = Timer::new()?;
+        // zero would disarm it
+        timer.set(after.max(Duration::from_nanos(1)), None)?;
+        Ok(timer)
+    }
+
+    /// expires every `interval`, starting one `interval` from now
+    pub fn periodic(interval: Duration) -> Result<Timer, Errno> {
+        let timer = Timer::new()?;
+        timer.set(interval, Some(interval))?;
+        Ok(timer)
+    }
+
+    /// expire after `after` and then every `interval`, if given. A zero `after` disarms the timer.
+    pub fn set(&self, after: Duration, interval: Option<Duration>) -> Result<(), Errno> {
+        let spec = Itimerspec {
+            interval: interval.unwrap_or_default().into(),
+            value: after.into()
+        };
+        unsafe { timerfd::settime(self.fd, &spec) }
+    }
+
+    pub fn cancel(&self) -> Result<(), Errno> {
+        self.set(Duration::from_secs(0), None)
+    }
+
+    /// expirations since the last read, without blocking.
+    /// Not needed with `reactor::register_counter`, which reads them for the `WakeUp`.
+    pub fn read(&self) -> Result<u64, Errno> {
+        let mut buf = [0u8; 8];
+        match unsafe { sys::read(self.fd, &mut buf) } {
+            Ok(_) => Ok(u64::from_ne_bytes(buf)),
+            Err(EAGAIN) => Ok(0),
+            Err(e) => Err(e)
+        }
+    }
+}
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = sys::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::Instant;
+use crate::dispatch::Cid;
+
+/// what happened
+#[derive(Debug, Clone)]
+pub enum TraceKind {
+    /// `cid` sent a message of type `msg` to `to`
+    Send { to: Cid, msg: &'static str },
+
+    /// `cid` was resumed with a message of type `msg`
+    Receive { msg: &'static str },
+
+    /// `cid` was woken up by the poller
+    WakeUp,
+
+    /// `cid` spawned `child`
+    Spawn { child: Cid },
+
+    /// `cid` terminated
+    Exit,
+}
+
+/// a single trace event
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub time: Instant,
+    pub cid: Cid,
+    pub kind: TraceKind,
+}
+
+/// where trace events go
+pub enum Tracer {
+    /// each event is sent to this process as a `TraceEvent` message.
+    /// events caused by the tracer itself are not reported.
+    Process(Cid),
+
+    /// the callback is invoked on the dispatcher thread for each event
+    Callback(Box<dyn FnMut(&TraceEvent)>),
+}
@@ -0,0 +1,626 @@
+//! io_uring backend
+//!
+//! A dispatcher created with `Backend::Uring` uses a ring as its reactor:
+//! registrations become poll requests and the dispatcher waits in `io_uring_enter`.
+//! Processes can also submit reads, writes, accepts and timeouts with the functions below
+//! and receive a `Completion` message when the operation is done.
+//! Reads and writes go through a pool of buffers registered with the kernel.
+//!
+//! Submissions are flushed each time the dispatcher waits,
+//! so a busy dispatcher submits many operations with a single `io_uring_enter`.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use std::{fmt, mem, ptr, slice};
+use crate::dispatch::Cid;
+use crate::message::Envelope;
+use crate::reactor::{Reactor, Token, WakeUp, read_counter};
+use crate::sys::{self, Errno, Timespec, epoll::Flags, uring::{self, Sqe, Cqe, Params, Op}};
+
+const EBUSY: Errno = libc::EBUSY as Errno;
+const ENOBUFS: Errno = libc::ENOBUFS as Errno;
+const EINTR: Errno = libc::EINTR as Errno;
+const EAGAIN: Errno = libc::EAGAIN as Errno;
+const ENOENT: Errno = libc::ENOENT as Errno;
+const EINVAL: Errno = libc::EINVAL as Errno;
+
+/// set in the user data of requests the ring makes for itself. Their completions are not delivered.
+const INTERNAL: u64 = 1 << 63;
+
+/// identifies a submitted operation
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OpId(u64);
+
+/// sent to the process that submitted an operation once it is done
+#[derive(Debug)]
+pub enum Completion {
+    Read { op: OpId, result: Result<FixedBuf, Errno> },
+    Write { op: OpId, result: Result<usize, Errno> },
+
+    /// the accepted socket is non-blocking
+    Accept { op: OpId, result: Result<RawFd, Errno> },
+    Timeout { op: OpId, result: Result<(), Errno> },
+}
+
+enum Pending {
+    Read(u16),
+    Write(u16),
+    Accept,
+    Timeout(Box<Timespec>),
+    Poll(Token),
+}
+
+/// a request of the ring itself that did not fit into the submission queue, pushed by the next `wait`
+enum Deferred {
+    /// poll for a registration
+    Arm(Token),
+
+    /// `PollRemove` or `TimeoutRemove` of a request
+    Remove(Op, u64),
+}
+
+/// a file descriptor registered through `Reactor`
+struct Registration {
+    fd: RawFd,
+    owner: Cid,
+    flags: Flags,
+
+    /// see `Reactor::register_counter`
+    counter: bool,
+
+    /// user data of the poll request in flight
+    armed: Option<u64>,
+}
+
+struct BufPool {
+    // not accessed after setup, the kernel and `FixedBuf`s go through `base`
+    _mem: Vec<u8>,
+    base: *mut u8,
+    size: usize,
+    free: RefCell<Vec<u16>>,
+}
+impl BufPool {
+    /// fails with `EINVAL` unless there are 1 to `u16::MAX` buffers of at least one byte,
+    /// and all of them fit in memory
+    fn new(count: usize, size: usize) -> Result<BufPool, Errno> {
+        if count == 0 || count > u16::MAX as usize || size == 0 {
+            return Err(EINVAL);
+        }
+        let len = count.checked_mul(size).ok_or(EINVAL)?;
+        let mut mem = vec![0; len];
+        Ok(BufPool {
+            base: mem.as_mut_ptr(),
+            _mem: mem,
+            size,
+            free: RefCell::new((0 .. count as u16).rev().collect()),
+        })
+    }
+    fn get(&self) -> Option<u16> {
+        self.free.borrow_mut().pop()
+    }
+    fn put(&self, index: u16) {
+        self.free.borrow_mut().push(index);
+    }
+    fn ptr(&self, index: u16) -> *mut u8 {
+        unsafe { self.base.add(index as usize * self.size) }
+    }
+    fn iovecs(&self) -> Vec<libc::iovec> {
+        (0 .. self.free.borrow().len() as u16).map(|i| libc::iovec {
+            iov_base: self.ptr(i) as *mut _,
+            iov_len: self.size
+        }).collect()
+    }
+}
+
+/// a registered buffer holding the data of a read.
+/// It goes back to the pool when dropped, so don't hold on to it.
+pub struct FixedBuf {
+    pool: Rc<BufPool>,
+    index: u16,
+    len: usize,
+}
+impl Deref for FixedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.pool.ptr(self.index), self.len) }
+    }
+}
+impl Drop for FixedBuf {
+    fn drop(&mut self) {
+        self.pool.put(self.index);
+    }
+}
+impl fmt::Debug for FixedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FixedBuf({} bytes)", self.len)
+    }
+}
+
+/// the ring fd and its mappings, released in `Drop`
+struct Mappings {
+    fd: RawFd,
+    maps: Vec<(*mut u8, usize)>,
+}
+impl Mappings {
+    unsafe fn map(&mut self, len: usize, offset: i64) -> Result<*mut u8, Errno> {
+        let ptr = uring::mmap(self.fd, len, offset)?;
+        self.maps.push((ptr, len));
+        Ok(ptr)
+    }
+}
+impl Drop for Mappings {
+    fn drop(&mut self) {
+        unsafe {
+            for &(ptr, len) in &self.maps {
+                let _ = uring::munmap(ptr, len);
+            }
+            let _ = sys::close(self.fd);
+        }
+    }
+}
+
+pub struct Uring {
+    // first, so the ring is closed before the buffers and timespecs the kernel may still use are freed
+    mappings: Mappings,
+
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+
+    to_submit: Cell<u32>,
+    pending: RefCell<HashMap<u64, (Cid, Pending)>>,
+    next_id: Cell<u64>,
+    buffers: Rc<BufPool>,
+    registrations: RefCell<HashMap<Token, Registration>>,
+    deferred: RefCell<Vec<Deferred>>,
+    woken: Cell<bool>,
+
+    /// the timeout of the current `wait`, and the user data of its request (0 if none)
+    wait_timeout: Cell<Timespec>,
+    wait_timer: Cell<u64>,
+}
+
+impl Uring {
+    /// a ring with `entries` submission slots and `buffers` registered buffers of `buffer_size` bytes
+    pub fn new(entries: u32, buffers: usize, buffer_size: usize) -> Result<Uring, Errno> {
+        let pool = BufPool::new(buffers, buffer_size)?;
+        let mut p = Params::default();
+        let fd = unsafe { uring::setup(entries, &mut p)? };
+        let mut mappings = Mappings { fd, maps: vec![] };
+
+        unsafe {
+            let sq_len = p.sq_off.array as usize + p.sq_entries as usize * mem::size_of::<u32>();
+            let cq_len = p.cq_off.cqes as usize + p.cq_entries as usize * mem::size_of::<Cqe>();
+            let sqes_len = p.sq_entries as usize * mem::size_of::<Sqe>();
+            let sq = mappings.map(sq_len, uring::OFF_SQ_RING)?;
+            let cq = mappings.map(cq_len, uring::OFF_CQ_RING)?;
+            let sqes = mappings.map(sqes_len, uring::OFF_SQES)?;
+
+            uring::register_buffers(fd, &pool.iovecs())?;
+
+            Ok(Uring {
+                mappings,
+                sq_head: sq.add(p.sq_off.head as usize) as _,
+                sq_tail: sq.add(p.sq_off.tail as usize) as _,
+                sq_mask: *(sq.add(p.sq_off.ring_mask as usize) as *const u32),
+                sq_entries: *(sq.add(p.sq_off.ring_entries as usize) as *const u32),
+                sq_array: sq.add(p.sq_off.array as usize) as _,
+                sqes: sqes as _,
+                cq_head: cq.add(p.cq_off.head as usize) as _,
+                cq_tail: cq.add(p.cq_off.tail as usize) as _,
+                cq_mask: *(cq.add(p.cq_off.ring_mask as usize) as *const u32),
+                cqes: cq.add(p.cq_off.cqes as usize) as _,
+                to_submit: Cell::new(0),
+                pending: RefCell::new(HashMap::new()),
+                next_id: Cell::new(1),
+                buffers: Rc::new(pool),
+                registrations: RefCell::new(HashMap::new()),
+                deferred: RefCell::new(vec![]),
+                woken: Cell::new(false),
+                wait_timeout: Cell::new(Timespec::default()),
+                wait_timer: Cell::new(0),
+            })
+        }
+    }
+
+    /// append an entry to the submission queue. Flushes the queue if it is full.
+    fn push(&self, fill: impl FnOnce(&mut Sqe)) -> Result<(), Errno> {
+        unsafe {
+            let tail = (*self.sq_tail).load(Ordering::Relaxed);
+            if tail.wrapping_sub((*self.sq_head).load(Ordering::Acquire)) == self.sq_entries {
+                self.submit()?;
+                if tail.wrapping_sub((*self.sq_head).load(Ordering::Acquire)) == self.sq_entries {
+                    return Err(EBUSY);
+                }
+            }
+            let index = tail & self.sq_mask;
+            let sqe = &mut *self.sqes.add(index as usize);
+            *sqe = Sqe::default();
+            fill(sqe);
+            *self.sq_array.add(index as usize) = index;
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.to_submit.set(self.to_submit.get() + 1);
+        Ok(())
+    }
+
+    fn next_id(&self) -> u64 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        id
+    }
+
+    fn start(&self, owner: Cid, pending: Pending, fill: impl FnOnce(&mut Sqe)) -> Result<OpId, (Errno, Pending)> {
+        let id = self.next_id();
+        match self.push(|sqe| { fill(sqe); sqe.user_data = id; }) {
+            Ok(()) => {
+                self.pending.borrow_mut().insert(id, (owner, pending));
+                Ok(OpId(id))
+            }
+            Err(e) => Err((e, pending))
+        }
+    }
+
+    /// read up to one buffer from `fd`, at `offset` or the current position.
+    /// Fails with `ENOBUFS` if all registered buffers are in use.
+    pub fn read(&self, owner: Cid, fd: RawFd, offset: Option<u64>) -> Result<OpId, Errno> {
+        let index = self.buffers.get().ok_or(ENOBUFS)?;
+        let (addr, len) = (self.buffers.ptr(index), self.buffers.size);
+        self.start(owner, Pending::Read(index), |sqe| {
+            sqe.opcode = Op::ReadFixed as u8;
+            sqe.fd = fd;
+            sqe.off = offset.unwrap_or(u64::MAX);
+            sqe.addr = addr as u64;
+            sqe.len = len as u32;
+            sqe.buf_index = index;
+        }).map_err(|(e, _)| { self.buffers.put(index); e })
+    }
+
+    /// write `data` to `fd`. `data` is copied into a registered buffer,
+    /// so at most one buffer worth of data is written. The completion tells how much.
+    pub fn write(&self, owner: Cid, fd: RawFd, data: &[u8], offset: Option<u64>) -> Result<OpId, Errno> {
+        let index = self.buffers.get().ok_or(ENOBUFS)?;
+        let addr = self.buffers.ptr(index);
+        let len = data.len().min(self.buffers.size);
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), addr, len);
+        }
+        self.start(owner, Pending::Write(index), |sqe| {
+            sqe.opcode = Op::WriteFixed as u8;
+            sqe.fd = fd;
+            sqe.off = offset.unwrap_or(u64::MAX);
+            sqe.addr = addr as u64;
+            sqe.len = len as u32;
+            sqe.buf_index = index;
+        }).map_err(|(e, _)| { self.buffers.put(index); e })
+    }
+
+    /// accept a connection on the listening socket `fd`
+    pub fn accept(&self, owner: Cid, fd: RawFd) -> Result<OpId, Errno> {
+        self.start(owner, Pending::Accept, |sqe| {
+            sqe.opcode = Op::Accept as u8;
+            sqe.fd = fd;
+            sqe.op_flags = (libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC) as u32;
+        }).map_err(|(e, _)| e)
+    }
+
+    /// complete after `after` has passed
+    pub fn timeout(&self, owner: Cid, after: Duration) -> Result<OpId, Errno> {
+        let ts = Box::new(Timespec::from(after));
+        let addr = &*ts as *const Timespec as u64;
+        self.start(owner, Pending::Timeout(ts), |sqe| {
+            sqe.opcode = Op::Timeout as u8;
+            sqe.fd = -1;
+            sqe.addr = addr;
+            sqe.len = 1;
+        }).map_err(|(e, _)| e)
+    }
+
+    /// a request whose completion is not delivered to anyone
+    fn push_internal(&self, fill: impl FnOnce(&mut Sqe)) -> Result<u64, Errno> {
+        let id = INTERNAL | self.next_id();
+        self.push(|sqe| { fill(sqe); sqe.user_data = id; })?;
+        Ok(id)
+    }
+
+    /// hand queued entries to the kernel and wait for `min_complete` completions
+    fn submit_and_wait(&self, min_complete: u32) -> Result<(), Errno> {
+        loop {
+            let n = self.to_submit.get();
+            if n == 0 && min_complete == 0 {
+                return Ok(());
+            }
+            let flags = if min_complete > 0 { uring::ENTER_GETEVENTS } else { 0 };
+            match unsafe { uring::enter(self.mappings.fd, n, min_complete, flags) } {
+                Ok(done) => {
+                    self.to_submit.set(n - done);
+                    return Ok(());
+                }
+                Err(EINTR) => continue,
+                // the completion queue is full, reaping makes room
+                Err(EAGAIN) | Err(EBUSY) => return Ok(()),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn submit(&self) -> Result<(), Errno> {
+        self.submit_and_wait(0)
+    }
+
+    fn has_completions(&self) -> bool {
+        unsafe {
+            (*self.cq_head).load(Ordering::Relaxed) != (*self.cq_tail).load(Ordering::Acquire)
+        }
+    }
+
+    /// take all completions off the completion queue
+    fn reap(&self, out: &mut Vec<(Cid, Envelope)>) {
+        let mut polled = vec![];
+        {
+            let mut pending = self.pending.borrow_mut();
+            unsafe {
+                let mut head = (*self.cq_head).load(Ordering::Relaxed);
+                let tail = (*self.cq_tail).load(Ordering::Acquire);
+                while head != tail {
+                    let Cqe { user_data, res, .. } = *self.cqes.add((head & self.cq_mask) as usize);
+                    head = head.wrapping_add(1);
+                    if user_data == self.wait_timer.get() {
+                        self.wait_timer.set(0);
+                    }
+                    match pending.remove(&user_data) {
+                        Some((owner, Pending::Poll(token))) => polled.push((owner, token, res)),
+                        Some((owner, p)) => out.push((owner, Envelope::pack(self.complete(OpId(user_data), p, res)))),
+                        // internal, or a poll request that was cancelled
+                        None => {}
+                    }
+                }
+                (*self.cq_head).store(head, Ordering::Release);
+            }
+        }
+
+        // poll requests fire once, so re-arm them to get level triggered registrations
+        let mut registrations = self.registrations.borrow_mut();
+        for (owner, token, res) in polled {
+            let reg = match registrations.get_mut(&token) {
+                Some(reg) => reg,
+                None => continue
+            };
+            reg.armed = None;
+            if res < 0 {
+                out.push((owner, Envelope::pack(WakeUp::new(token, Flags::Err))));
+                // nothing left to poll, the owner closed the fd without deregistering it
+                if res == -libc::EBADF {
+                    debug!("io_uring: poll of {:?} -> {}", token, -res);
+                    continue;
+                }
+            } else {
+                let flags = Flags::from_bits_truncate(res as u32);
+                match reg.counter {
+                    false => out.push((owner, Envelope::pack(WakeUp::new(token, flags)))),
+                    true => if let Some(count) = read_counter(reg.fd) {
+                        out.push((owner, Envelope::pack(WakeUp::with_count(token, flags, count))));
+                    }
+                }
+            }
+            if !reg.flags.contains(Flags::OneShot) {
+                self.arm(token, reg);
+            }
+        }
+    }
+
+    fn add(&self, mut reg: Registration) -> Token {
+        let token = Token(self.next_id());
+        self.arm(token, &mut reg);
+        self.registrations.borrow_mut().insert(token, reg);
+        token
+    }
+
+    /// submit a poll request for `reg`, or defer it if the submission queue is full
+    fn arm(&self, token: Token, reg: &mut Registration) {
+        let mask = (reg.flags - Flags::OneShot) | Flags::Err | Flags::Hup;
+        let fd = reg.fd;
+        let op = self.start(reg.owner, Pending::Poll(token), |sqe| {
+            sqe.opcode = Op::PollAdd as u8;
+            sqe.fd = fd;
+            sqe.op_flags = mask.bits();
+        });
+        match op {
+            Ok(op) => reg.armed = Some(op.0),
+            Err((e, _)) => {
+                debug!("io_uring: deferring poll of {:?} -> {}", token, e);
+                self.deferred.borrow_mut().push(Deferred::Arm(token));
+            }
+        }
+    }
+
+    fn disarm(&self, reg: &mut Registration) {
+        if let Some(id) = reg.armed.take() {
+            self.pending.borrow_mut().remove(&id);
+            self.remove(Op::PollRemove, id);
+        }
+    }
+
+    /// cancel the poll or timeout request `id`, or defer that if the submission queue is full
+    fn remove(&self, op: Op, id: u64) {
+        let pushed = self.push_internal(|sqe| {
+            sqe.opcode = op as u8;
+            sqe.fd = -1;
+            sqe.addr = id;
+        });
+        if let Err(e) = pushed {
+            debug!("io_uring: deferring {:?} of {} -> {}", op, id, e);
+            self.deferred.borrow_mut().push(Deferred::Remove(op, id));
+        }
+    }
+
+    /// push the deferred requests. Returns false if some still did not fit.
+    fn push_deferred(&self) -> bool {
+        let deferred = mem::take(&mut *self.deferred.borrow_mut());
+        if deferred.len() == 0 {
+            return true;
+        }
+        let mut registrations = self.registrations.borrow_mut();
+        for d in deferred {
+            match d {
+                // it may have been removed, or armed again by `modify`, since
+                Deferred::Arm(token) => if let Some(reg) = registrations.get_mut(&token) {
+                    if reg.armed.is_none() {
+                        self.arm(token, reg);
+                    }
+                },
+                Deferred::Remove(op, id) => self.remove(op, id),
+            }
+        }
+        self.deferred.borrow().len() == 0
+    }
+
+    fn complete(&self, op: OpId, pending: Pending, res: i32) -> Completion {
+        let result = if res < 0 { Err(-res as Errno) } else { Ok(res as usize) };
+        match pending {
+            Pending::Read(index) => match result {
+                Ok(len) => Completion::Read { op, result: Ok(FixedBuf { pool: self.buffers.clone(), index, len }) },
+                Err(e) => {
+                    self.buffers.put(index);
+                    Completion::Read { op, result: Err(e) }
+                }
+            },
+            Pending::Write(index) => {
+                self.buffers.put(index);
+                Completion::Write { op, result }
+            }
+            Pending::Accept => Completion::Accept { op, result: result.map(|fd| fd as RawFd) },
+            Pending::Timeout(_) => match res {
+                r if r == -libc::ETIME => Completion::Timeout { op, result: Ok(()) },
+                _ => Completion::Timeout { op, result: result.map(|_| ()) }
+            },
+            Pending::Poll(_) => unreachable!()
+        }
+    }
+}
+impl Reactor for Uring {
+    /// errors of `fd` arrive as a `WakeUp` with `Flags::Err`.
+    /// Poll requests are level triggered, so `EdgeTriggered` fails with `EINVAL`.
+    fn register(&self, fd: RawFd, owner: Cid, flags: Flags) -> Result<Token, Errno> {
+        if flags.contains(Flags::EdgeTriggered) {
+            return Err(EINVAL);
+        }
+        Ok(self.add(Registration { fd, owner, flags, counter: false, armed: None }))
+    }
+    fn register_counter(&self, fd: RawFd, owner: Cid) -> Result<Token, Errno> {
+        Ok(self.add(Registration { fd, owner, flags: Flags::In, counter: true, armed: None }))
+    }
+    fn modify(&self, _fd: RawFd, token: Token, flags: Flags) -> Result<(), Errno> {
+        if flags.contains(Flags::EdgeTriggered) {
+            return Err(EINVAL);
+        }
+        let mut registrations = self.registrations.borrow_mut();
+        let reg = registrations.get_mut(&token).ok_or(ENOENT)?;
+        self.disarm(reg);
+        reg.flags = flags;
+        self.arm(token, reg);
+        Ok(())
+    }
+    fn deregister(&self, _fd: RawFd, token: Token) -> Result<(), Errno> {
+        let mut reg = self.registrations.borrow_mut().remove(&token).ok_or(ENOENT)?;
+        self.disarm(&mut reg);
+        Ok(())
+    }
+    fn wait(&self, ready: &mut Vec<(Cid, Envelope)>, mut timeout: Option<Duration>) -> Result<(), Errno> {
+        // don't block with registrations that are not armed
+        let stuck = !self.push_deferred();
+        if self.woken.replace(false) || self.has_completions() || stuck {
+            timeout = Some(Duration::from_secs(0));
+        }
+        match timeout {
+            Some(t) if t == Duration::from_secs(0) => self.submit()?,
+            Some(t) => {
+                self.wait_timeout.set(Timespec::from(t));
+                let addr = self.wait_timeout.as_ptr() as u64;
+                let timer = self.push_internal(|sqe| {
+                    sqe.opcode = Op::Timeout as u8;
+                    sqe.fd = -1;
+                    sqe.addr = addr;
+                    sqe.len = 1;
+                });
+                match timer {
+                    Ok(id) => {
+                        self.wait_timer.set(id);
+                        self.submit_and_wait(1)?;
+                    }
+                    // no room for the timer, so don't block
+                    Err(EBUSY) => self.submit()?,
+                    Err(e) => return Err(e)
+                }
+            }
+            None => self.submit_and_wait(1)?,
+        }
+        self.reap(ready);
+
+        // woken up by something else, the timer is not needed anymore
+        let timer = self.wait_timer.replace(0);
+        if timer != 0 {
+            self.remove(Op::TimeoutRemove, timer);
+        }
+        Ok(())
+    }
+    fn wake(&self) {
+        self.woken.set(true);
+    }
+}
+impl AsRawFd for Uring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.mappings.fd
+    }
+}
+
+thread_local! {
+    /// the ring of the dispatcher that is currently running on this thread
+    static CURRENT: RefCell<Option<Rc<Uring>>> = RefCell::new(None);
+}
+
+/// makes `ring` the target of the functions below until dropped
+pub(crate) struct Enter {
+    prev: Option<Rc<Uring>>
+}
+impl Drop for Enter {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|c| *c.borrow_mut() = prev);
+    }
+}
+pub(crate) fn enter(ring: &Rc<Uring>) -> Enter {
+    let prev = CURRENT.with(|c| c.borrow_mut().replace(ring.clone()));
+    Enter { prev }
+}
+
+/// the ring of the dispatcher that is currently running.
+/// Panics when called outside of a dispatcher, or if the dispatcher does not use io_uring.
+pub fn current() -> Rc<Uring> {
+    CURRENT.with(|c| c.borrow().clone()).expect("dispatcher does not use io_uring")
+}
+
+pub fn read(owner: Cid, fd: RawFd, offset: Option<u64>) -> Result<OpId, Errno> {
+    current().read(owner, fd, offset)
+}
+pub fn write(owner: Cid, fd: RawFd, data: &[u8], offset: Option<u64>) -> Result<OpId, Errno> {
+    current().write(owner, fd, data, offset)
+}
+pub fn accept(owner: Cid, fd: RawFd) -> Result<OpId, Errno> {
+    current().accept(owner, fd)
+}
+pub fn timeout(owner: Cid, after: Duration) -> Result<OpId, Errno> {
+    current().timeout(owner, after)
+}
@@ -0,0 +1,387 @@
+//! File system events as messages, through inotify
+//!
+//! ```ignore
+//! let watcher = Watcher::new()?;
+//! watcher.add("/etc/myservice", true)?;
+//! d.spawn2(Box::new(move |id| watch::server(id, watcher, config_loader)));
+//! ```
+//!
+//! A rename inside the watched tree is reported as a single `Move`.
+//! Moving something out of the tree is reported as `Delete`, moving something in as `Create`.
+//! A path given to `Watcher::add` that is renamed itself is reported as `Delete` and no longer watched,
+//! inotify does not tell where it went.
+
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::path::{Path, PathBuf};
+use std::{fs, mem, ptr};
+use crate::prelude::*;
+use crate::reactor::{self, WakeUp};
+use crate::signals::{Subscribe, Unsubscribe};
+use crate::sys::{self, Errno, epoll::Flags, inotify::{self, Mask}};
+
+const EAGAIN: Errno = libc::EAGAIN as Errno;
+const EINVAL: Errno = libc::EINVAL as Errno;
+const BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Create,
+    Modify,
+    Delete,
+
+    /// renamed from `from` to the path of the event
+    Move { from: PathBuf },
+}
+
+/// sent to the subscribers of a `server`
+#[derive(Clone, Debug)]
+pub struct FsEvent {
+    pub path: PathBuf,
+    pub kind: Kind,
+}
+
+struct Watch {
+    path: PathBuf,
+    recursive: bool,
+
+    /// added with `Watcher::add`, not found while walking a tree
+    root: bool,
+}
+
+/// an inotify instance and what it watches
+pub struct Watcher {
+    fd: RawFd,
+    watches: RefCell<HashMap<i32, Watch>>,
+}
+
+impl Watcher {
+    pub fn new() -> Result<Watcher, Errno> {
+        let fd = unsafe { inotify::init()? };
+        Ok(Watcher { fd, watches: RefCell::new(HashMap::new()) })
+    }
+
+    /// watch `path`, a file or a directory.
+    /// With `recursive`, directories below it are watched as well, including ones created later.
+    pub fn add(&self, path: impl AsRef<Path>, recursive: bool) -> Result<(), Errno> {
+        let path = path.as_ref();
+        self.add_watch(path, recursive, true)?;
+        if recursive && path.is_dir() {
+            self.add_tree(path, &mut vec![], false);
+        }
+        Ok(())
+    }
+
+    fn add_watch(&self, path: &Path, recursive: bool, root: bool) -> Result<(), Errno> {
+        let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|_| EINVAL)?;
+        let mask = Mask::Create | Mask::Modify | Mask::Delete | Mask::MovedFrom | Mask::MovedTo
+            | Mask::DeleteSelf | Mask::MoveSelf;
+        let wd = unsafe { inotify::add_watch(self.fd, &cpath, mask)? };
+        self.watches.borrow_mut().insert(wd, Watch { path: path.into(), recursive, root });
+        Ok(())
+    }
+
+    /// watch the directories below `dir`. With `emit`, whatever is found is reported as created,
+    /// because it may have appeared before the watches were in place.
+    fn add_tree(&self, dir: &Path, events: &mut Vec<FsEvent>, emit: bool) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("read_dir({:?}) -> {}", dir, e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if emit {
+                events.push(FsEvent { path: path.clone(), kind: Kind::Create });
+            }
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                if self.add_watch(&path, true, false).is_ok() {
+                    self.add_tree(&path, events, emit);
+                }
+            }
+        }
+    }
+
+    /// a directory was renamed inside the tree, so are the watches below it
+    fn rename_tree(&self, from: &Path, to: &Path) {
+        for w in self.watches.borrow_mut().values_mut() {
+            if let Ok(rest) = w.path.strip_prefix(from) {
+                w.path = to.join(rest);
+            }
+        }
+    }
+
+    /// a directory left the tree, stop watching it
+    fn remove_tree(&self, dir: &Path) {
+        let mut watches = self.watches.borrow_mut();
+        let gone: Vec<i32> = watches.iter()
+            .filter(|(_, w)| !w.root && w.path.starts_with(dir))
+            .map(|(&wd, _)| wd)
+            .collect();
+        for wd in gone {
+            watches.remove(&wd);
+            unsafe {
+                let _ = inotify::rm_watch(self.fd, wd);
+            }
+        }
+    }
+
+    /// a path added with `add` was renamed, stop watching it and the tree below it
+    fn remove_root(&self, wd: i32, dir: &Path) {
+        let mut watches = self.watches.borrow_mut();
+        let gone: Vec<i32> = watches.iter()
+            .filter(|(&w, watch)| w == wd || (!watch.root && watch.path.starts_with(dir)))
+            .map(|(&wd, _)| wd)
+            .collect();
+        for wd in gone {
+            watches.remove(&wd);
+            unsafe {
+                let _ = inotify::rm_watch(self.fd, wd);
+            }
+        }
+    }
+
+    /// read all pending events from the kernel
+    fn read_raw(&self) -> Vec<(i32, Mask, u32, PathBuf)> {
+        let mut raw = vec![];
+        let mut buf = vec![0u8; BUF_SIZE];
+        loop {
+            let n = match unsafe { sys::read(self.fd, &mut buf) } {
+                Ok(0) | Err(EAGAIN) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    debug!("read from inotify -> {:?}", e);
+                    break;
+                }
+            };
+            let mut pos = 0;
+            while pos + mem::size_of::<inotify::Event>() <= n {
+                let event = unsafe { ptr::read_unaligned(buf[pos ..].as_ptr() as *const inotify::Event) };
+                let start = pos + mem::size_of::<inotify::Event>();
+                let name = &buf[start .. start + event.len as usize];
+                let name = &name[.. name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                raw.push((event.wd, Mask::from_bits_truncate(event.mask), event.cookie, OsStr::from_bytes(name).into()));
+                pos = start + event.len as usize;
+            }
+        }
+        raw
+    }
+
+    /// read all pending events and turn them into `FsEvent`s
+    fn read(&self) -> Vec<FsEvent> {
+        let mut raw: Vec<_> = self.read_raw().into_iter().map(Some).collect();
+        let mut events = vec![];
+        for i in 0 .. raw.len() {
+            let (wd, mask, cookie, name) = match raw[i].take() {
+                Some(r) => r,
+                None => continue
+            };
+            if mask.contains(Mask::QueueOverflow) {
+                warn!("inotify queue overflow, events were lost");
+                continue;
+            }
+            if mask.contains(Mask::Ignored) {
+                self.watches.borrow_mut().remove(&wd);
+                continue;
+            }
+            let (path, recursive, root) = match self.watches.borrow().get(&wd) {
+                // events about the watched file or directory itself have no name
+                Some(w) if name.as_os_str().is_empty() => (w.path.clone(), w.recursive, w.root),
+                Some(w) => (w.path.join(&name), w.recursive, w.root),
+                None => continue
+            };
+            let is_dir = mask.contains(Mask::IsDir);
+
+            if mask.intersects(Mask::DeleteSelf | Mask::MoveSelf) {
+                // below the root, the parent directory already reported it
+                if root {
+                    if mask.contains(Mask::MoveSelf) {
+                        self.remove_root(wd, &path);
+                    }
+                    events.push(FsEvent { path, kind: Kind::Delete });
+                }
+            } else if mask.contains(Mask::Create) {
+                events.push(FsEvent { path: path.clone(), kind: Kind::Create });
+                if is_dir && recursive && self.add_watch(&path, true, false).is_ok() {
+                    self.add_tree(&path, &mut events, true);
+                }
+            } else if mask.contains(Mask::Modify) {
+                events.push(FsEvent { path, kind: Kind::Modify });
+            } else if mask.contains(Mask::Delete) {
+                events.push(FsEvent { path, kind: Kind::Delete });
+            } else if mask.contains(Mask::MovedFrom) {
+                // both halves of a rename are queued by the same syscall, so the other one was read too
+                let to = raw[i + 1 ..].iter().position(|r| match *r {
+                    Some((_, m, c, _)) => m.contains(Mask::MovedTo) && c == cookie,
+                    None => false
+                });
+                match to.and_then(|j| raw[i + 1 + j].take()) {
+                    Some((to_wd, _, _, to_name)) => {
+                        let to = match self.watches.borrow().get(&to_wd) {
+                            Some(w) => w.path.join(&to_name),
+                            None => continue
+                        };
+                        if is_dir {
+                            self.rename_tree(&path, &to);
+                        }
+                        events.push(FsEvent { path: to, kind: Kind::Move { from: path } });
+                    }
+                    None => {
+                        if is_dir {
+                            self.remove_tree(&path);
+                        }
+                        events.push(FsEvent { path, kind: Kind::Delete });
+                    }
+                }
+            } else if mask.contains(Mask::MovedTo) {
+                events.push(FsEvent { path: path.clone(), kind: Kind::Create });
+                if is_dir && recursive && self.add_watch(&path, true, false).is_ok() {
+                    self.add_tree(&path, &mut events, true);
+                }
+            }
+        }
+        events
+    }
+}
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = sys::close(self.fd);
+        }
+    }
+}
+
+/// sends an `FsEvent` to `reciever` and every subscriber for each change `watcher` sees
+pub fn server(id: Cid, watcher: Watcher, reciever: Cid) -> GenBox {
+    let watcher = reactor::register(watcher, id, Flags::In);
+    let mut subscribers = vec![reciever];
+
+    Box::pin(Box::new(move |_: ResumeArg| {
+        let watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                error!("watch: register -> {}", e);
+                done!();
+            }
+        };
+        loop {
+            recv!{
+                WakeUp, _ => {
+                    for event in watcher.read() {
+                        for i in 0 .. subscribers.len() {
+                            send!(subscribers[i], event.clone());
+                        }
+                    }
+                },
+                Subscribe, Subscribe(cid) => subscribers.push(cid),
+                Unsubscribe, Unsubscribe(cid) => subscribers.retain(|&s| s != cid)
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an empty directory for one test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("emp-watch-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn kinds(events: Vec<FsEvent>) -> Vec<(PathBuf, Kind)> {
+        events.into_iter().map(|e| (e.path, e.kind)).collect()
+    }
+
+    #[test]
+    fn rename_inside_the_tree_is_one_move() {
+        let dir = scratch("move");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), b"").unwrap();
+        let watcher = Watcher::new().unwrap();
+        watcher.add(&dir, true).unwrap();
+
+        fs::rename(dir.join("a"), dir.join("sub/b")).unwrap();
+        assert_eq!(kinds(watcher.read()), [(dir.join("sub/b"), Kind::Move { from: dir.join("a") })]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn moving_out_is_a_delete_and_moving_in_a_create() {
+        let dir = scratch("out");
+        let outside = scratch("outside");
+        fs::write(dir.join("a"), b"").unwrap();
+        fs::write(outside.join("b"), b"").unwrap();
+        let watcher = Watcher::new().unwrap();
+        watcher.add(&dir, true).unwrap();
+
+        fs::rename(dir.join("a"), outside.join("a")).unwrap();
+        fs::rename(outside.join("b"), dir.join("b")).unwrap();
+        assert_eq!(kinds(watcher.read()), [
+            (dir.join("a"), Kind::Delete),
+            (dir.join("b"), Kind::Create),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn a_renamed_directory_keeps_its_watches() {
+        let dir = scratch("rename-dir");
+        fs::create_dir(dir.join("sub")).unwrap();
+        let watcher = Watcher::new().unwrap();
+        watcher.add(&dir, true).unwrap();
+
+        fs::rename(dir.join("sub"), dir.join("moved")).unwrap();
+        fs::write(dir.join("moved/f"), b"").unwrap();
+        assert_eq!(kinds(watcher.read()), [
+            (dir.join("moved"), Kind::Move { from: dir.join("sub") }),
+            (dir.join("moved/f"), Kind::Create),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn events_of_a_watched_file_carry_its_path() {
+        let dir = scratch("file");
+        let file = dir.join("f");
+        fs::write(&file, b"").unwrap();
+        let watcher = Watcher::new().unwrap();
+        watcher.add(&file, false).unwrap();
+
+        fs::write(&file, b"changed").unwrap();
+        fs::remove_file(&file).unwrap();
+        let events = kinds(watcher.read());
+        assert_eq!(events.first(), Some(&(file.clone(), Kind::Modify)));
+        assert_eq!(events.last(), Some(&(file.clone(), Kind::Delete)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_renamed_root_is_deleted() {
+        let dir = scratch("rename-root");
+        let moved = scratch("rename-root-moved");
+        fs::remove_dir(&moved).unwrap();
+        let watcher = Watcher::new().unwrap();
+        watcher.add(&dir, true).unwrap();
+
+        fs::rename(&dir, &moved).unwrap();
+        fs::write(moved.join("f"), b"").unwrap();
+        assert_eq!(kinds(watcher.read()), [(dir.clone(), Kind::Delete)]);
+        assert!(watcher.watches.borrow().is_empty());
+        fs::remove_dir_all(&moved).unwrap();
+    }
+}